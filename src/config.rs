@@ -0,0 +1,363 @@
+use crate::err::{ManagerError, ManagerErrorKind};
+use crate::http::auth::Tokens;
+use crate::manager::credentials::Credential;
+use crate::manager::proxy::{self, Proxies};
+use crate::manager::schedule::Schedule;
+use std::env;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+// the settings, each one can come from the config file, a DOWNMGR_<KEY> environment variable
+// or a command line flag, in increasing precedence
+pub const KEYS: [&str; 26] = [
+    "addr",
+    "workers",
+    "downloads",
+    "incomplete",
+    "database",
+    "segments",
+    "max_attempts",
+    "limit",
+    "window",
+    "token",
+    "tokens",
+    "url",
+    "ca",
+    "client_cert",
+    "client_key",
+    "tls_cert",
+    "tls_key",
+    "tls_client_ca",
+    "socket",
+    "socket_mode",
+    "cookies",
+    "cookie_jar",
+    "proxy",
+    "no_proxy",
+    "proxy_rules",
+    "netrc",
+];
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    // the address to bind to in case of a daemon, or to connect to otherwise. None when it's
+    // set to a unix socket, the daemon then doesn't listen on tcp at all
+    pub addr: Option<SocketAddr>,
+    // max number of parallel downloads
+    pub workers: u32,
+    pub downloads: String,
+    // where unfinished downloads are kept, next to the final file if empty
+    pub incomplete: String,
+    pub database: String,
+    // defaults of the jobs that don't set them
+    pub segments: u32,
+    pub max_attempts: u32,
+    // global bytes per second, 0 for none
+    pub limit: u64,
+    pub schedule: Schedule,
+    // sent by the cli to the daemon
+    pub token: String,
+    // accepted by the daemon, anyone can use the api if there are none
+    pub tokens: Tokens,
+    // where the cli finds the daemon, derived from the address if empty
+    pub url: String,
+    // pem files the cli trusts the daemon's certificate with and identifies itself with
+    pub ca: String,
+    pub client_cert: String,
+    pub client_key: String,
+    // pem files of the daemon's certificate and key, plain http if empty
+    pub tls_cert: String,
+    pub tls_key: String,
+    // clients have to present a certificate signed by one of these, if set
+    pub tls_client_ca: String,
+    // the daemon also serves the api on this unix socket, and the cli prefers it if set
+    pub socket: String,
+    // permissions of the socket, who can connect to it can use the api without a token
+    pub socket_mode: u32,
+    // a netscape cookies.txt, e.g. exported from a browser, added to the jar on start and reload
+    pub cookies: String,
+    // where the daemon keeps the cookies it sends and the ones sites set
+    pub cookie_jar: String,
+    // what the downloads connect through, the proxy, no_proxy and proxy_rules settings
+    pub proxies: Proxies,
+    // machine entries answering the 401s of the downloads, missing is the same as empty
+    pub netrc: String,
+    // hosts and their logins from the [credentials] section, going before the netrc
+    pub credentials: Vec<(String, Credential)>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let mut proxies = Proxies::default();
+        proxies.set_no_proxy(&env_any(&["NO_PROXY", "no_proxy"]).unwrap_or_default());
+        Config {
+            addr: Some(SocketAddr::from(([127, 0, 0, 1], 8000))),
+            workers: 5,
+            downloads: shellexpand::tilde("~/Downloads").to_string(),
+            incomplete: "".into(),
+            database: xdg_dir("XDG_DATA_HOME", "~/.local/share")
+                .join("downloads.db")
+                .to_string_lossy()
+                .into(),
+            segments: 4,
+            max_attempts: 5,
+            limit: 0,
+            schedule: Schedule::default(),
+            token: "".into(),
+            tokens: Tokens::default(),
+            url: "".into(),
+            ca: "".into(),
+            client_cert: "".into(),
+            client_key: "".into(),
+            tls_cert: "".into(),
+            tls_key: "".into(),
+            tls_client_ca: "".into(),
+            socket: "".into(),
+            socket_mode: 0o600,
+            cookies: "".into(),
+            cookie_jar: xdg_dir("XDG_DATA_HOME", "~/.local/share")
+                .join("cookies.txt")
+                .to_string_lossy()
+                .into(),
+            // the usual variables until the settings say otherwise, each scheme has its own
+            // and ALL_PROXY covers the ones without
+            proxies: Proxies {
+                http: env_proxy(&["HTTP_PROXY", "http_proxy", "ALL_PROXY", "all_proxy"]),
+                https: env_proxy(&["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"]),
+                ..proxies
+            },
+            netrc: shellexpand::tilde("~/.netrc").to_string(),
+            credentials: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    // reads the config file and the environment on top of the defaults, a missing file is
+    // only an error if its path was given explicitly
+    pub fn load(path: Option<&str>) -> Result<Self, ManagerError> {
+        let mut config = Config::default();
+        let explicit = path
+            .map(|p| p.to_string())
+            .or_else(|| env::var("DOWNMGR_CONFIG").ok());
+        let path = match &explicit {
+            Some(p) => PathBuf::from(shellexpand::tilde(p).to_string()),
+            None => default_path(),
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(s) => config.merge_toml(&s).map_err(|e| ManagerError {
+                kind: e.kind,
+                msg: format!("{}: {}", path.display(), e.msg),
+            })?,
+            Err(e) if e.kind() == ErrorKind::NotFound && explicit.is_none() => {}
+            Err(e) => {
+                return Err(ManagerError {
+                    kind: ManagerErrorKind::IO,
+                    msg: format!("couldn't read {}: {}", path.display(), e),
+                })
+            }
+        }
+        for key in KEYS.iter() {
+            if let Ok(v) = env::var(format!("DOWNMGR_{}", key.to_uppercase())) {
+                config.set(key, &v)?;
+            }
+        }
+        Ok(config)
+    }
+    fn merge_toml(&mut self, s: &str) -> Result<(), ManagerError> {
+        let table: toml::value::Table = toml::from_str(s)?;
+        for (key, value) in table.iter() {
+            if key == "credentials" {
+                self.credentials = parse_credentials(value)?;
+                continue;
+            }
+            let value = match value {
+                toml::Value::String(s) => s.clone(),
+                toml::Value::Integer(i) => i.to_string(),
+                // a list of windows or tokens
+                toml::Value::Array(a) => a
+                    .iter()
+                    .map(|v| v.as_str().map(|s| s.to_string()).unwrap_or_default())
+                    .collect::<Vec<_>>()
+                    .join(","),
+                _ => {
+                    return Err(ManagerError {
+                        kind: ManagerErrorKind::InvalidConfig,
+                        msg: format!("unexpected value for {}: {}", key, value),
+                    })
+                }
+            };
+            self.set(key, &value)?;
+        }
+        Ok(())
+    }
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ManagerError> {
+        self.set_value(key, value).map_err(|e| ManagerError {
+            kind: e.kind,
+            msg: format!("{} = {:?}: {}", key, value, e.msg),
+        })
+    }
+    fn set_value(&mut self, key: &str, value: &str) -> Result<(), ManagerError> {
+        match key {
+            "addr" => match value.strip_prefix("unix://") {
+                Some(path) => {
+                    self.socket = shellexpand::tilde(path).to_string();
+                    self.addr = None;
+                }
+                None => self.addr = Some(value.parse()?),
+            },
+            "workers" => self.workers = value.parse()?,
+            "downloads" => self.downloads = shellexpand::tilde(value).to_string(),
+            "incomplete" => self.incomplete = shellexpand::tilde(value).to_string(),
+            "database" => self.database = shellexpand::tilde(value).to_string(),
+            "segments" => self.segments = value.parse()?,
+            "max_attempts" => self.max_attempts = value.parse()?,
+            "limit" => self.limit = parse_rate(value)?,
+            "window" => self.schedule = value.parse()?,
+            "token" => self.token = value.into(),
+            "tokens" => self.tokens = value.parse()?,
+            "url" => self.url = value.into(),
+            "ca" => self.ca = shellexpand::tilde(value).to_string(),
+            "client_cert" => self.client_cert = shellexpand::tilde(value).to_string(),
+            "client_key" => self.client_key = shellexpand::tilde(value).to_string(),
+            "tls_cert" => self.tls_cert = shellexpand::tilde(value).to_string(),
+            "tls_key" => self.tls_key = shellexpand::tilde(value).to_string(),
+            "tls_client_ca" => self.tls_client_ca = shellexpand::tilde(value).to_string(),
+            "socket" => self.socket = shellexpand::tilde(value).to_string(),
+            "socket_mode" => self.socket_mode = u32::from_str_radix(value, 8)?,
+            "cookies" => self.cookies = shellexpand::tilde(value).to_string(),
+            "cookie_jar" => self.cookie_jar = shellexpand::tilde(value).to_string(),
+            "proxy" => self.proxies.set_default(value)?,
+            "no_proxy" => self.proxies.set_no_proxy(value),
+            "proxy_rules" => self.proxies.set_rules(value)?,
+            "netrc" => self.netrc = shellexpand::tilde(value).to_string(),
+            _ => {
+                return Err(ManagerError {
+                    kind: ManagerErrorKind::InvalidConfig,
+                    msg: format!("unknown setting {}", key),
+                })
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    // the unix socket if there's one, https when the daemon has a certificate, a daemon elsewhere
+    // needs the url set
+    pub fn client_url(&self) -> String {
+        if !self.url.is_empty() {
+            return self.url.clone();
+        }
+        let addr = match self.addr {
+            Some(addr) if self.socket.is_empty() => addr,
+            _ => return format!("unix://{}", self.socket),
+        };
+        let scheme = if self.tls_cert.is_empty() {
+            "http"
+        } else {
+            "https"
+        };
+        format!("{}://{}", scheme, addr)
+    }
+    // an api open to other machines needs tokens, the daemon doesn't start without them
+    pub fn check_tokens(&self) -> Result<(), ManagerError> {
+        match self.addr {
+            Some(addr) if !addr.ip().is_loopback() && self.tokens.is_empty() => Err(ManagerError {
+                kind: ManagerErrorKind::InvalidConfig,
+                msg: format!(
+                    "{} isn't a loopback address, set tokens to protect the api",
+                    addr
+                ),
+            }),
+            _ => Ok(()),
+        }
+    }
+    // the certificate and the key go together, and client certificates need tls
+    pub fn check_tls(&self) -> Result<(), ManagerError> {
+        let invalid = |msg: &str| ManagerError {
+            kind: ManagerErrorKind::InvalidConfig,
+            msg: msg.into(),
+        };
+        if self.tls_cert.is_empty() != self.tls_key.is_empty() {
+            return Err(invalid("tls_cert and tls_key have to be set together"));
+        }
+        if !self.tls_client_ca.is_empty() && self.tls_cert.is_empty() {
+            return Err(invalid("tls_client_ca needs tls_cert and tls_key"));
+        }
+        if self.client_cert.is_empty() != self.client_key.is_empty() {
+            return Err(invalid(
+                "client_cert and client_key have to be set together",
+            ));
+        }
+        Ok(())
+    }
+}
+
+// host = { user = "...", password = "..." } entries
+fn parse_credentials(value: &toml::Value) -> Result<Vec<(String, Credential)>, ManagerError> {
+    let invalid = |msg: String| ManagerError {
+        kind: ManagerErrorKind::InvalidConfig,
+        msg,
+    };
+    let table = value
+        .as_table()
+        .ok_or_else(|| invalid("credentials should be a section of hosts".into()))?;
+    let mut credentials = Vec::new();
+    for (host, entry) in table.iter() {
+        let field = |name: &str| entry.get(name).and_then(|v| v.as_str()).map(String::from);
+        let user = field("user")
+            .filter(|u| !u.is_empty())
+            .ok_or_else(|| invalid(format!("the credentials of {} need a user", host)))?;
+        credentials.push((
+            host.to_lowercase(),
+            Credential {
+                user,
+                password: field("password").unwrap_or_default(),
+            },
+        ));
+    }
+    Ok(credentials)
+}
+
+fn env_proxy(vars: &[&str]) -> Option<url::Url> {
+    env_any(vars).and_then(|v| proxy::parse(&v).ok().flatten())
+}
+
+fn env_any(vars: &[&str]) -> Option<String> {
+    vars.iter()
+        .filter_map(|v| env::var(v).ok())
+        .find(|v| !v.is_empty())
+}
+
+// $XDG_CONFIG_HOME/downmgr/config.toml
+pub fn default_path() -> PathBuf {
+    xdg_dir("XDG_CONFIG_HOME", "~/.config").join("config.toml")
+}
+
+fn xdg_dir(var: &str, fallback: &str) -> PathBuf {
+    let base = match env::var(var) {
+        // relative paths are invalid according to the spec
+        Ok(v) if v.starts_with('/') => v,
+        _ => shellexpand::tilde(fallback).to_string(),
+    };
+    PathBuf::from(base).join("downmgr")
+}
+
+// parses a rate in bytes per second, with an optional K, M or G suffix
+pub fn parse_rate(v: &str) -> Result<u64, ManagerError> {
+    let v = v.trim();
+    let (num, multiplier) = match v.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&v[..v.len() - 1], 1024),
+        Some('M') => (&v[..v.len() - 1], 1024 * 1024),
+        Some('G') => (&v[..v.len() - 1], 1024 * 1024 * 1024),
+        _ => (v, 1),
+    };
+    num.parse::<u64>()?
+        .checked_mul(multiplier)
+        .ok_or_else(|| ManagerError {
+            kind: ManagerErrorKind::InvalidConfig,
+            msg: format!("{} is too large", v),
+        })
+}
@@ -12,6 +12,17 @@ pub enum ManagerErrorKind {
     HTTPError,
     DownloadJobNotFound,
     DownloadJobNameAlreadyExist,
+    InvalidJobState,
+    InvalidChecksum,
+    InvalidTime,
+    InvalidConfig,
+    UnsupportedProtocol,
+    InvalidMetalink,
+    InvalidVariant,
+    InvalidHeader,
+    InvalidCookies,
+    InvalidProxy,
+    InvalidCredentials,
     ParseIntError,
     ParseBoolError,
 }
@@ -43,6 +54,17 @@ impl fmt::Display for ManagerErrorKind {
                 ManagerErrorKind::ParseBoolError => "errorr parsing bool".to_string(),
                 ManagerErrorKind::DownloadJobNameAlreadyExist =>
                     "download job name already exist".to_string(),
+                ManagerErrorKind::InvalidJobState => "invalid job state".to_string(),
+                ManagerErrorKind::InvalidChecksum => "invalid checksum".to_string(),
+                ManagerErrorKind::InvalidTime => "invalid time".to_string(),
+                ManagerErrorKind::InvalidConfig => "invalid config".to_string(),
+                ManagerErrorKind::UnsupportedProtocol => "unsupported protocol".to_string(),
+                ManagerErrorKind::InvalidMetalink => "invalid metalink".to_string(),
+                ManagerErrorKind::InvalidVariant => "invalid variant".to_string(),
+                ManagerErrorKind::InvalidHeader => "invalid header".to_string(),
+                ManagerErrorKind::InvalidCookies => "invalid cookies".to_string(),
+                ManagerErrorKind::InvalidProxy => "invalid proxy".to_string(),
+                ManagerErrorKind::InvalidCredentials => "invalid credentials".to_string(),
             }
         )
     }
@@ -72,6 +94,15 @@ impl From<std::string::FromUtf8Error> for ManagerError {
     }
 }
 
+impl From<toml::de::Error> for ManagerError {
+    fn from(err: toml::de::Error) -> Self {
+        ManagerError {
+            kind: ManagerErrorKind::InvalidConfig,
+            msg: err.to_string(),
+        }
+    }
+}
+
 impl From<rusqlite::Error> for ManagerError {
     fn from(err: rusqlite::Error) -> Self {
         ManagerError {
@@ -0,0 +1,97 @@
+use crate::err::{ManagerError, ManagerErrorKind};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Scope {
+    Read,
+    // also allows reading
+    Write,
+}
+
+// the tokens the api accepts, anyone can use the api if there are none
+#[derive(Clone, Debug, Default)]
+pub struct Tokens(Vec<(String, Scope)>);
+
+// comma separated, each one optionally followed by :read or :write, write if left out
+impl FromStr for Tokens {
+    type Err = ManagerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = Vec::new();
+        for token in s.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            let (token, scope) = match token.rsplit_once(':') {
+                Some((t, "read")) => (t, Scope::Read),
+                Some((t, "write")) => (t, Scope::Write),
+                _ => (token, Scope::Write),
+            };
+            if token.is_empty() {
+                return Err(ManagerError {
+                    kind: ManagerErrorKind::InvalidConfig,
+                    msg: "empty token".into(),
+                });
+            }
+            tokens.push((token.to_string(), scope));
+        }
+        Ok(Tokens(tokens))
+    }
+}
+
+impl Tokens {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    fn scope(&self, token: &str) -> Option<Scope> {
+        self.0
+            .iter()
+            .find(|(t, _)| constant_time_eq(t.as_bytes(), token.as_bytes()))
+            .map(|(_, scope)| *scope)
+    }
+}
+
+// doesn't stop at the first difference, so the time taken doesn't tell how much of a guess was
+// right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// request guards, a route taking one of them needs a bearer token with at least that scope
+pub struct CanRead;
+pub struct CanWrite;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CanRead {
+    type Error = &'static str;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        authorize(req, Scope::Read).map(|_| CanRead)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CanWrite {
+    type Error = &'static str;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        authorize(req, Scope::Write).map(|_| CanWrite)
+    }
+}
+
+fn authorize(req: &Request, needed: Scope) -> Outcome<(), &'static str> {
+    let tokens = match req.rocket().state::<Tokens>() {
+        Some(t) if !t.is_empty() => t,
+        _ => return Outcome::Success(()),
+    };
+    let token = req
+        .headers()
+        .get_one("Authorization")
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match token.and_then(|t| tokens.scope(t.trim())) {
+        None => Outcome::Error((Status::Unauthorized, "missing or invalid token")),
+        Some(scope) if scope < needed => {
+            Outcome::Error((Status::Forbidden, "the token is read-only"))
+        }
+        Some(_) => Outcome::Success(()),
+    }
+}
@@ -1,26 +1,75 @@
-use super::types::{Add, Cancel, Error};
+use super::types::{Add, AddBatch, Cancel, Credentials, Error, Limit};
+use super::unix;
+use crate::config::Config;
 use crate::err::ManagerError;
-use crate::err::ManagerErrorKind::{DecodingError, HTTPError};
-use crate::manager::types::{AckCommand, InfoResponse, ListResponse};
+use crate::err::ManagerErrorKind::{DecodingError, HTTPError, InvalidConfig};
+use crate::manager::types::{
+    AckCommand, AddBatchCommand, AddBatchResponse, AddCommand, Event, InfoResponse, ListResponse,
+    Reorder,
+};
 use reqwest;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use reqwest::{Certificate, Identity, RequestBuilder};
 use url::Url;
 
 pub struct HTTPClient {
+    // the api root, e.g. http://127.0.0.1:8000/api/v1/
+    api: Url,
     base: Url,
     cl: reqwest::Client,
+    // the requests go through this unix socket instead, if set
+    socket: Option<String>,
 }
 
 impl HTTPClient {
-    pub async fn new(url: &str) -> Result<Self, ManagerError> {
-        let url = Url::parse(url)?;
-        let url = url.join("api/v1/jobs/")?;
+    // the token is sent with every request unless it's empty, a custom ca is trusted on top of the
+    // system ones and the client certificate is presented to daemons that ask for one
+    pub async fn new(config: &Config) -> Result<Self, ManagerError> {
+        let url = config.client_url();
+        let (api, socket) = match url.strip_prefix("unix://") {
+            // the host is only there to make the urls valid
+            Some(path) => (
+                Url::parse("http://localhost/api/v1/")?,
+                Some(shellexpand::tilde(path).to_string()),
+            ),
+            None => (Url::parse(&url)?.join("api/v1/")?, None),
+        };
+        let mut headers = HeaderMap::new();
+        if !config.token.is_empty() {
+            let mut value = HeaderValue::from_str(&format!("Bearer {}", config.token))
+                .map_err(|e| invalid_config(format!("invalid token: {}", e)))?;
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        let mut builder = reqwest::Client::builder().default_headers(headers);
+        if !config.ca.is_empty() {
+            let ca = Certificate::from_pem(&read_pem(&config.ca)?)
+                .map_err(|e| invalid_config(format!("invalid ca {}: {}", config.ca, e)))?;
+            builder = builder.add_root_certificate(ca);
+        }
+        if !config.client_cert.is_empty() {
+            let identity = Identity::from_pkcs8_pem(
+                &read_pem(&config.client_cert)?,
+                &read_pem(&config.client_key)?,
+            )
+            .map_err(|e| invalid_config(format!("invalid client certificate: {}", e)))?;
+            builder = builder.identity(identity);
+        }
         Ok(Self {
-            base: url,
-            cl: reqwest::Client::new(),
+            base: api.join("jobs/")?,
+            api,
+            cl: builder.build()?,
+            socket,
         })
     }
+    async fn send(&self, req: RequestBuilder) -> Result<reqwest::Response, ManagerError> {
+        match &self.socket {
+            Some(path) => unix::send(path, req.build()?).await,
+            None => Ok(req.send().await?),
+        }
+    }
     pub async fn list(&self) -> Result<ListResponse, ManagerError> {
-        let res = self.cl.get(self.base.as_str()).send().await?;
+        let res = self.send(self.cl.get(self.base.as_str())).await?;
         match res.status() {
             reqwest::StatusCode::OK => res.json().await.map_err(|e| ManagerError {
                 kind: DecodingError,
@@ -44,7 +93,7 @@ impl HTTPClient {
     pub async fn info(&self, name: &str) -> Result<InfoResponse, ManagerError> {
         let url = self.base.join(name)?;
 
-        let res = self.cl.get(url.as_str()).send().await?;
+        let res = self.send(self.cl.get(url.as_str())).await?;
         match res.status() {
             reqwest::StatusCode::OK => res.json().await.map_err(|e| ManagerError {
                 kind: DecodingError,
@@ -74,7 +123,72 @@ impl HTTPClient {
         let message = Cancel { forget, delete };
         let url = self.base.join(name)?;
 
-        let res = self.cl.delete(url.as_str()).json(&message).send().await?;
+        let res = self
+            .send(self.cl.delete(url.as_str()).json(&message))
+            .await?;
+        match res.status() {
+            reqwest::StatusCode::OK => Ok(AckCommand),
+            _ => {
+                let e = res.json::<Error>().await.map_err(|e| ManagerError {
+                    kind: DecodingError,
+                    msg: e.to_string(),
+                });
+                match e {
+                    Err(e) => Err(e),
+                    Ok(v) => Err(ManagerError {
+                        kind: HTTPError,
+                        msg: v.to_string(),
+                    }),
+                }
+            }
+        }
+    }
+    pub async fn pause(&self, name: &str) -> Result<AckCommand, ManagerError> {
+        let url = self.base.join(&format!("{}/pause", name))?;
+
+        let res = self.send(self.cl.post(url.as_str())).await?;
+        match res.status() {
+            reqwest::StatusCode::OK => Ok(AckCommand),
+            _ => {
+                let e = res.json::<Error>().await.map_err(|e| ManagerError {
+                    kind: DecodingError,
+                    msg: e.to_string(),
+                });
+                match e {
+                    Err(e) => Err(e),
+                    Ok(v) => Err(ManagerError {
+                        kind: HTTPError,
+                        msg: v.to_string(),
+                    }),
+                }
+            }
+        }
+    }
+    pub async fn reorder(&self, name: &str, reorder: Reorder) -> Result<AckCommand, ManagerError> {
+        let url = self.base.join(&format!("{}/priority", name))?;
+
+        let res = self.send(self.cl.put(url.as_str()).json(&reorder)).await?;
+        match res.status() {
+            reqwest::StatusCode::OK => Ok(AckCommand),
+            _ => {
+                let e = res.json::<Error>().await.map_err(|e| ManagerError {
+                    kind: DecodingError,
+                    msg: e.to_string(),
+                });
+                match e {
+                    Err(e) => Err(e),
+                    Ok(v) => Err(ManagerError {
+                        kind: HTTPError,
+                        msg: v.to_string(),
+                    }),
+                }
+            }
+        }
+    }
+    pub async fn resume(&self, name: &str) -> Result<AckCommand, ManagerError> {
+        let url = self.base.join(&format!("{}/resume", name))?;
+
+        let res = self.send(self.cl.post(url.as_str())).await?;
         match res.status() {
             reqwest::StatusCode::OK => Ok(AckCommand),
             _ => {
@@ -92,17 +206,95 @@ impl HTTPClient {
             }
         }
     }
-    pub async fn add(&self, url: &str, name: Option<&str>) -> Result<AckCommand, ManagerError> {
-        let message = Add {
-            url: url.into(),
+    pub async fn events(&self) -> Result<EventStream, ManagerError> {
+        let url = self.api.join("events/")?;
+
+        let res = self.send(self.cl.get(url.as_str())).await?;
+        match res.status() {
+            reqwest::StatusCode::OK => Ok(EventStream {
+                res,
+                buf: Vec::new(),
+            }),
+            _ => {
+                let e = res.json::<Error>().await.map_err(|e| ManagerError {
+                    kind: DecodingError,
+                    msg: e.to_string(),
+                });
+                match e {
+                    Err(e) => Err(e),
+                    Ok(v) => Err(ManagerError {
+                        kind: HTTPError,
+                        msg: v.to_string(),
+                    }),
+                }
+            }
+        }
+    }
+    pub async fn limit(
+        &self,
+        name: Option<&str>,
+        rate_limit: u64,
+    ) -> Result<AckCommand, ManagerError> {
+        let message = Limit {
             name: name.map(|s| s.into()),
+            rate_limit,
+        };
+        let url = self.api.join("limits/")?;
+
+        let res = self.send(self.cl.put(url.as_str()).json(&message)).await?;
+        match res.status() {
+            reqwest::StatusCode::OK => Ok(AckCommand),
+            _ => {
+                let e = res.json::<Error>().await.map_err(|e| ManagerError {
+                    kind: DecodingError,
+                    msg: e.to_string(),
+                });
+                match e {
+                    Err(e) => Err(e),
+                    Ok(v) => Err(ManagerError {
+                        kind: HTTPError,
+                        msg: v.to_string(),
+                    }),
+                }
+            }
+        }
+    }
+    pub async fn add_credentials(
+        &self,
+        host: &str,
+        user: &str,
+        password: &str,
+    ) -> Result<AckCommand, ManagerError> {
+        let message = Credentials {
+            host: host.into(),
+            user: user.into(),
+            password: password.into(),
         };
+        let url = self.api.join("credentials/")?;
+
+        let res = self.send(self.cl.post(url.as_str()).json(&message)).await?;
+        match res.status() {
+            reqwest::StatusCode::OK => Ok(AckCommand),
+            _ => {
+                let e = res.json::<Error>().await.map_err(|e| ManagerError {
+                    kind: DecodingError,
+                    msg: e.to_string(),
+                });
+                match e {
+                    Err(e) => Err(e),
+                    Ok(v) => Err(ManagerError {
+                        kind: HTTPError,
+                        msg: v.to_string(),
+                    }),
+                }
+            }
+        }
+    }
+    pub async fn add(&self, cmd: AddCommand) -> Result<AckCommand, ManagerError> {
+        let message = Add::from(cmd);
 
         let res = self
-            .cl
-            .post(self.base.as_str())
-            .json(&message)
-            .send()
+            .send(self.cl.post(self.base.as_str()).json(&message))
             .await?;
         match res.status() {
             reqwest::StatusCode::CREATED => Ok(AckCommand),
@@ -121,4 +313,83 @@ impl HTTPClient {
             }
         }
     }
+    pub async fn add_batch(&self, cmd: AddBatchCommand) -> Result<AddBatchResponse, ManagerError> {
+        let message = AddBatch::from(cmd);
+
+        let res = self
+            .send(
+                self.cl
+                    .post(self.base.join("batch")?.as_str())
+                    .json(&message),
+            )
+            .await?;
+        match res.status() {
+            reqwest::StatusCode::OK => res.json().await.map_err(|e| ManagerError {
+                kind: DecodingError,
+                msg: e.to_string(),
+            }),
+            _ => {
+                let e = res.json::<Error>().await.map_err(|e| ManagerError {
+                    kind: DecodingError,
+                    msg: e.to_string(),
+                });
+                match e {
+                    Err(e) => Err(e),
+                    Ok(v) => Err(ManagerError {
+                        kind: HTTPError,
+                        msg: v.to_string(),
+                    }),
+                }
+            }
+        }
+    }
+}
+
+fn read_pem(path: &str) -> Result<Vec<u8>, ManagerError> {
+    std::fs::read(path).map_err(|e| invalid_config(format!("couldn't read {}: {}", path, e)))
+}
+
+fn invalid_config(msg: String) -> ManagerError {
+    ManagerError {
+        kind: InvalidConfig,
+        msg,
+    }
+}
+
+// the job events pushed by the daemon as server-sent events
+pub struct EventStream {
+    res: reqwest::Response,
+    buf: Vec<u8>,
+}
+
+impl EventStream {
+    // returns None once the daemon closes the stream
+    pub async fn next(&mut self) -> Option<Result<Event, ManagerError>> {
+        loop {
+            if let Some(i) = self.buf.windows(2).position(|w| w == b"\n\n") {
+                let raw: Vec<u8> = self.buf.drain(..i + 2).collect();
+                let raw = String::from_utf8_lossy(&raw);
+                let data: Vec<&str> = raw
+                    .lines()
+                    .filter_map(|l| l.strip_prefix("data:"))
+                    .map(|l| l.strip_prefix(' ').unwrap_or(l))
+                    .collect();
+                // comments and heartbeats carry no data
+                if data.is_empty() {
+                    continue;
+                }
+                return Some(
+                    rocket::serde::json::from_str(&data.join("\n")).map_err(|e| ManagerError {
+                        kind: DecodingError,
+                        msg: e.to_string(),
+                    }),
+                );
+            }
+            match self.res.chunk().await {
+                Ok(Some(chunk)) => self.buf.extend_from_slice(&chunk),
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
 }
@@ -1,4 +1,6 @@
+pub mod auth;
 pub mod client;
 pub mod rest;
 mod types;
+pub mod unix;
 pub use client::HTTPClient;
@@ -1,13 +1,20 @@
-use super::types::{Add, ApiResponse, Cancel, Error};
+use super::auth::{CanRead, CanWrite};
+use super::types::{Add, AddBatch, ApiResponse, Cancel, Credentials, Error, Limit};
 use crate::err::ManagerErrorKind;
 use crate::manager::client::ManagerClient;
-use crate::manager::types::{AckCommand, InfoResponse, ListResponse};
+use crate::manager::types::{
+    AckCommand, AddBatchResponse, CredentialsCommand, InfoResponse, ListResponse, Reorder,
+};
 use rocket::http::Status;
+use rocket::response::stream::{Event as SseEvent, EventStream};
 use rocket::serde::json::Json;
-use rocket::{Request, State};
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::{Request, Shutdown, State};
 
 #[get("/")]
 pub async fn list(
+    _auth: CanRead,
     state: &State<ManagerClient>,
 ) -> Result<ApiResponse<ListResponse>, ApiResponse<Error>> {
     match state.list().await {
@@ -24,6 +31,7 @@ pub async fn list(
 
 #[get("/<name>")]
 pub async fn info(
+    _auth: CanRead,
     state: &State<ManagerClient>,
     name: &str,
 ) -> Result<ApiResponse<InfoResponse>, ApiResponse<Error>> {
@@ -47,6 +55,7 @@ pub async fn info(
 
 #[delete("/<name>", format = "application/json", data = "<msg>")]
 pub async fn cancel(
+    _auth: CanWrite,
     state: &State<ManagerClient>,
     name: &str,
     msg: Json<Cancel>,
@@ -71,10 +80,11 @@ pub async fn cancel(
 
 #[post("/", format = "application/json", data = "<msg>")]
 pub async fn add(
+    _auth: CanWrite,
     state: &State<ManagerClient>,
     msg: Json<Add>,
 ) -> Result<ApiResponse<AckCommand>, ApiResponse<Error>> {
-    match state.add(&msg.url, msg.name.as_deref()).await {
+    match state.add(msg.into_inner().into()).await {
         Ok(v) => Ok(ApiResponse {
             json: Json(v),
             status: Status::Created,
@@ -82,6 +92,15 @@ pub async fn add(
         Err(e) => {
             let code = match &e.kind {
                 ManagerErrorKind::InvalidAddress => Status::BadRequest,
+                ManagerErrorKind::InvalidChecksum => Status::BadRequest,
+                ManagerErrorKind::UnsupportedProtocol => Status::BadRequest,
+                ManagerErrorKind::DownloadJobNameAlreadyExist => Status::Conflict,
+                ManagerErrorKind::InvalidMetalink => Status::BadRequest,
+                ManagerErrorKind::InvalidVariant => Status::BadRequest,
+                ManagerErrorKind::InvalidHeader => Status::BadRequest,
+                ManagerErrorKind::InvalidCookies => Status::BadRequest,
+                ManagerErrorKind::InvalidProxy => Status::BadRequest,
+                ManagerErrorKind::InvalidTime => Status::BadRequest,
                 _ => Status::InternalServerError,
             };
             Err(ApiResponse {
@@ -92,6 +111,194 @@ pub async fn add(
     }
 }
 
+// the jobs that couldn't be added are reported in the response, the rest are added anyway
+#[post("/batch", format = "application/json", data = "<msg>")]
+pub async fn add_batch(
+    _auth: CanWrite,
+    state: &State<ManagerClient>,
+    msg: Json<AddBatch>,
+) -> Result<ApiResponse<AddBatchResponse>, ApiResponse<Error>> {
+    match state.add_batch(msg.into_inner().into()).await {
+        Ok(v) => Ok(ApiResponse {
+            json: Json(v),
+            status: Status::Ok,
+        }),
+        Err(e) => Err(ApiResponse {
+            json: Json(e.into()),
+            status: Status::InternalServerError,
+        }),
+    }
+}
+
+#[post("/<name>/pause")]
+pub async fn pause(
+    _auth: CanWrite,
+    state: &State<ManagerClient>,
+    name: &str,
+) -> Result<ApiResponse<AckCommand>, ApiResponse<Error>> {
+    match state.pause(name).await {
+        Ok(v) => Ok(ApiResponse {
+            json: Json(v),
+            status: Status::Ok,
+        }),
+        Err(e) => {
+            let code = match &e.kind {
+                ManagerErrorKind::DownloadJobNotFound => Status::NotFound,
+                ManagerErrorKind::InvalidJobState => Status::Conflict,
+                _ => Status::InternalServerError,
+            };
+            Err(ApiResponse {
+                json: Json(e.into()),
+                status: code,
+            })
+        }
+    }
+}
+
+#[put("/<name>/priority", format = "application/json", data = "<msg>")]
+pub async fn reorder(
+    _auth: CanWrite,
+    state: &State<ManagerClient>,
+    name: &str,
+    msg: Json<Reorder>,
+) -> Result<ApiResponse<AckCommand>, ApiResponse<Error>> {
+    match state.reorder(name, msg.into_inner()).await {
+        Ok(v) => Ok(ApiResponse {
+            json: Json(v),
+            status: Status::Ok,
+        }),
+        Err(e) => {
+            let code = match &e.kind {
+                ManagerErrorKind::DownloadJobNotFound => Status::NotFound,
+                ManagerErrorKind::InvalidJobState => Status::Conflict,
+                _ => Status::InternalServerError,
+            };
+            Err(ApiResponse {
+                json: Json(e.into()),
+                status: code,
+            })
+        }
+    }
+}
+
+#[post("/<name>/resume")]
+pub async fn resume(
+    _auth: CanWrite,
+    state: &State<ManagerClient>,
+    name: &str,
+) -> Result<ApiResponse<AckCommand>, ApiResponse<Error>> {
+    match state.resume(name).await {
+        Ok(v) => Ok(ApiResponse {
+            json: Json(v),
+            status: Status::Ok,
+        }),
+        Err(e) => {
+            let code = match &e.kind {
+                ManagerErrorKind::DownloadJobNotFound => Status::NotFound,
+                ManagerErrorKind::InvalidJobState => Status::Conflict,
+                _ => Status::InternalServerError,
+            };
+            Err(ApiResponse {
+                json: Json(e.into()),
+                status: code,
+            })
+        }
+    }
+}
+
+#[put("/", format = "application/json", data = "<msg>")]
+pub async fn limit(
+    _auth: CanWrite,
+    state: &State<ManagerClient>,
+    msg: Json<Limit>,
+) -> Result<ApiResponse<AckCommand>, ApiResponse<Error>> {
+    match state.limit(msg.name.as_deref(), msg.rate_limit).await {
+        Ok(v) => Ok(ApiResponse {
+            json: Json(v),
+            status: Status::Ok,
+        }),
+        Err(e) => {
+            let code = match &e.kind {
+                ManagerErrorKind::DownloadJobNotFound => Status::NotFound,
+                _ => Status::InternalServerError,
+            };
+            Err(ApiResponse {
+                json: Json(e.into()),
+                status: code,
+            })
+        }
+    }
+}
+
+#[post("/", format = "application/json", data = "<msg>")]
+pub async fn credentials(
+    _auth: CanWrite,
+    state: &State<ManagerClient>,
+    msg: Json<Credentials>,
+) -> Result<ApiResponse<AckCommand>, ApiResponse<Error>> {
+    let msg = msg.into_inner();
+    let cmd = CredentialsCommand {
+        host: msg.host,
+        user: msg.user,
+        password: msg.password,
+    };
+    match state.add_credentials(cmd).await {
+        Ok(v) => Ok(ApiResponse {
+            json: Json(v),
+            status: Status::Ok,
+        }),
+        Err(e) => {
+            let code = match &e.kind {
+                ManagerErrorKind::InvalidCredentials => Status::BadRequest,
+                _ => Status::InternalServerError,
+            };
+            Err(ApiResponse {
+                json: Json(e.into()),
+                status: code,
+            })
+        }
+    }
+}
+
+#[get("/")]
+pub fn events(
+    _auth: CanRead,
+    state: &State<ManagerClient>,
+    mut shutdown: Shutdown,
+) -> EventStream![] {
+    let mut events = state.subscribe();
+    EventStream! {
+        loop {
+            let event = select! {
+                event = events.recv() => match event {
+                    Ok(event) => event,
+                    Err(RecvError::Closed) => break,
+                    // a slow client misses some events instead of holding the daemon back
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+                _ = &mut shutdown => break,
+            };
+            yield SseEvent::json(&event).event(event.kind.to_string());
+        }
+    }
+}
+
+#[catch(401)]
+pub fn unauthorized(_: &Request) -> ApiResponse<Error> {
+    ApiResponse {
+        json: Json("missing or invalid token".into()),
+        status: Status::Unauthorized,
+    }
+}
+
+#[catch(403)]
+pub fn forbidden(_: &Request) -> ApiResponse<Error> {
+    ApiResponse {
+        json: Json("the token is read-only".into()),
+        status: Status::Forbidden,
+    }
+}
+
 #[catch(404)]
 pub fn not_found(_: &Request) -> ApiResponse<Error> {
     ApiResponse {
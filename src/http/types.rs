@@ -1,4 +1,6 @@
 use crate::err::ManagerError;
+use crate::manager::types::{AddBatchCommand, AddCommand};
+use crate::types::RequestOptions;
 use rocket::http::{ContentType, Status};
 use rocket::response::{Responder, Response};
 use rocket::serde::{json::Json, Deserialize, Serialize};
@@ -7,8 +9,85 @@ use std::fmt;
 
 #[derive(Serialize, Deserialize)]
 pub struct Add {
+    // can be left out when adding a metalink
+    #[serde(default)]
     pub url: String,
     pub name: Option<String>,
+    pub segments: Option<u32>,
+    pub checksum: Option<String>,
+    pub max_attempts: Option<u32>,
+    pub rate_limit: Option<u64>,
+    pub priority: Option<i32>,
+    pub start_at: Option<u64>,
+    pub metalink: Option<String>,
+    #[serde(default)]
+    pub hls: bool,
+    pub variant: Option<String>,
+    // headers, cookies, user_agent and referer
+    #[serde(default)]
+    pub request: RequestOptions,
+    pub cookies_txt: Option<String>,
+}
+
+impl From<Add> for AddCommand {
+    fn from(a: Add) -> Self {
+        AddCommand {
+            url: a.url,
+            name: a.name,
+            segments: a.segments,
+            checksum: a.checksum,
+            max_attempts: a.max_attempts,
+            rate_limit: a.rate_limit,
+            priority: a.priority,
+            start_at: a.start_at,
+            metalink: a.metalink,
+            hls: a.hls,
+            variant: a.variant,
+            request: a.request,
+            cookies_txt: a.cookies_txt,
+        }
+    }
+}
+
+impl From<AddCommand> for Add {
+    fn from(c: AddCommand) -> Self {
+        Add {
+            url: c.url,
+            name: c.name,
+            segments: c.segments,
+            checksum: c.checksum,
+            max_attempts: c.max_attempts,
+            rate_limit: c.rate_limit,
+            priority: c.priority,
+            start_at: c.start_at,
+            metalink: c.metalink,
+            hls: c.hls,
+            variant: c.variant,
+            request: c.request,
+            cookies_txt: c.cookies_txt,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AddBatch {
+    pub jobs: Vec<Add>,
+}
+
+impl From<AddBatch> for AddBatchCommand {
+    fn from(a: AddBatch) -> Self {
+        AddBatchCommand {
+            jobs: a.jobs.into_iter().map(|j| j.into()).collect(),
+        }
+    }
+}
+
+impl From<AddBatchCommand> for AddBatch {
+    fn from(c: AddBatchCommand) -> Self {
+        AddBatch {
+            jobs: c.jobs.into_iter().map(|j| j.into()).collect(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -17,6 +96,22 @@ pub struct Cancel {
     pub delete: bool,
 }
 
+// limits a single job when a name is given, all downloads otherwise
+#[derive(Serialize, Deserialize)]
+pub struct Limit {
+    pub name: Option<String>,
+    pub rate_limit: u64,
+}
+
+// a login for the downloads from a host, answering its 401s
+#[derive(Serialize, Deserialize)]
+pub struct Credentials {
+    pub host: String,
+    pub user: String,
+    #[serde(default)]
+    pub password: String,
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum Error {
     Error(String),
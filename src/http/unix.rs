@@ -0,0 +1,235 @@
+use crate::err::{ManagerError, ManagerErrorKind};
+use bytes::{Bytes, BytesMut};
+use hyper::body::HttpBody;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Request, Response, StatusCode};
+use rand::{distributions::Alphanumeric, Rng};
+use rocket::http::{Header, Method};
+use rocket::local::asynchronous::Client;
+use rocket::{Build, Rocket};
+use std::fs::{DirBuilder, Permissions};
+use std::io::ErrorKind;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::oneshot;
+
+// serves the api on a unix socket. rocket 0.5 only listens on tcp, so the requests are handed
+// to a local instance of the app, which keeps the routes the same as over tcp but skips rocket's
+// connection handling: request bodies are capped here at the json limit before being passed on,
+// and the socket stops accepting on SIGINT or SIGTERM. Who can connect is decided by the
+// permissions of the socket
+pub async fn serve(rocket: Rocket<Build>, path: &str, mode: u32) -> Result<(), ManagerError> {
+    let client = Arc::new(Client::untracked(rocket).await.map_err(|e| ManagerError {
+        kind: ManagerErrorKind::IO,
+        msg: format!("couldn't start the api on the unix socket: {}", e),
+    })?);
+    let limit = client
+        .rocket()
+        .config()
+        .limits
+        .get("json")
+        .map(|l| l.as_u64())
+        .unwrap_or(1 << 20);
+    let listener = bind(path, mode)?;
+    let _bound = Bound(path.into());
+    println!("serving the api on {}", path);
+    let mut terminate = signal(SignalKind::terminate())?;
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => accepted?.0,
+            _ = tokio::signal::ctrl_c() => break,
+            _ = terminate.recv() => break,
+        };
+        let client = client.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| dispatch(client.clone(), req, limit));
+            if let Err(e) = Http::new()
+                .http1_only(true)
+                .serve_connection(stream, service)
+                .await
+            {
+                println!("unix socket connection failed {}", e)
+            }
+        });
+    }
+    Ok(())
+}
+
+// the socket is bound in a fresh private directory next to it and moved into place once its
+// permissions are set, so it's never connectable with the ones the umask gives
+fn bind(path: &str, mode: u32) -> Result<UnixListener, ManagerError> {
+    // left behind by a daemon that didn't exit cleanly, anything else at the path is kept
+    match std::fs::symlink_metadata(path) {
+        Ok(m) if m.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(ManagerError {
+                    kind: ManagerErrorKind::IO,
+                    msg: format!("another daemon is serving on {}", path),
+                });
+            }
+            std::fs::remove_file(path)?
+        }
+        Ok(_) => {
+            return Err(ManagerError {
+                kind: ManagerErrorKind::IO,
+                msg: format!("{} already exists and isn't a socket", path),
+            })
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let suffix: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(8)
+        .map(char::from)
+        .collect();
+    let dir = format!("{}.{}", path, suffix);
+    // fails rather than reuse a directory that's already there
+    DirBuilder::new().mode(0o700).create(&dir)?;
+    let tmp = Path::new(&dir).join("sock");
+    let res = UnixListener::bind(&tmp)
+        .and_then(|l| std::fs::set_permissions(&tmp, Permissions::from_mode(mode)).map(|_| l))
+        .and_then(|l| std::fs::rename(&tmp, path).map(|_| l));
+    let _ = std::fs::remove_file(&tmp);
+    let _ = std::fs::remove_dir(&dir);
+    Ok(res?)
+}
+
+// removes the socket once the api stops, also when the tcp one stopped first
+struct Bound(String);
+
+impl Drop for Bound {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+// reads the whole body unless it's over the limit
+async fn read_body(mut body: Body, limit: u64) -> Result<Option<Bytes>, hyper::Error> {
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if (buf.len() + chunk.len()) as u64 > limit {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(Some(buf.freeze()))
+}
+
+async fn dispatch(
+    client: Arc<Client>,
+    req: Request<Body>,
+    limit: u64,
+) -> Result<Response<Body>, hyper::Error> {
+    let (parts, body) = req.into_parts();
+    let body = match read_body(body, limit).await? {
+        Some(v) => v,
+        None => {
+            let mut res = Response::new(Body::empty());
+            *res.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
+            return Ok(res);
+        }
+    };
+    let (head_sender, head_receiver) = oneshot::channel();
+    let (mut body_sender, res_body) = Body::channel();
+    // the response borrows the client, so it's streamed from a task that owns both. Event streams
+    // only end when the connection does
+    tokio::spawn(async move {
+        let method = match Method::from_str(parts.method.as_str()) {
+            Ok(v) => v,
+            Err(_) => {
+                let _ =
+                    head_sender.send(Response::builder().status(StatusCode::METHOD_NOT_ALLOWED));
+                return;
+            }
+        };
+        let uri = parts
+            .uri
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/")
+            .to_string();
+        let mut req = client.req(method, uri);
+        for (name, value) in parts.headers.iter() {
+            if let Ok(value) = value.to_str() {
+                req.add_header(Header::new(name.as_str().to_string(), value.to_string()));
+            }
+        }
+        let mut res = req.body(&body).dispatch().await;
+        let mut head = Response::builder().status(res.status().code);
+        for header in res.headers().iter() {
+            head = head.header(header.name().as_str(), header.value());
+        }
+        if head_sender.send(head).is_err() {
+            return;
+        }
+        let mut buf = vec![0; 16 * 1024];
+        loop {
+            let n = match res.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            if body_sender
+                .send_data(Bytes::copy_from_slice(&buf[..n]))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+    let head = head_receiver
+        .await
+        .unwrap_or_else(|_| Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR));
+    Ok(head
+        .body(res_body)
+        .unwrap_or_else(|_| Response::new(Body::empty())))
+}
+
+// sends a request built by reqwest over the socket, one connection per request
+pub async fn send(path: &str, req: reqwest::Request) -> Result<reqwest::Response, ManagerError> {
+    let stream = UnixStream::connect(path).await.map_err(|e| ManagerError {
+        kind: ManagerErrorKind::IO,
+        msg: format!("couldn't connect to {}: {}", path, e),
+    })?;
+    let (mut sender, conn) = hyper::client::conn::handshake(stream)
+        .await
+        .map_err(http_error)?;
+    tokio::spawn(conn);
+    let uri = match req.url().query() {
+        Some(q) => format!("{}?{}", req.url().path(), q),
+        None => req.url().path().to_string(),
+    };
+    let mut builder = Request::builder()
+        .method(req.method().clone())
+        .uri(uri)
+        .header(hyper::header::HOST, "localhost");
+    for (name, value) in req.headers().iter() {
+        builder = builder.header(name, value);
+    }
+    let body = req
+        .body()
+        .and_then(|b| b.as_bytes())
+        .map(|b| b.to_vec())
+        .unwrap_or_default();
+    let req = builder.body(Body::from(body)).map_err(|e| ManagerError {
+        kind: ManagerErrorKind::HTTPError,
+        msg: e.to_string(),
+    })?;
+    let res = sender.send_request(req).await.map_err(http_error)?;
+    Ok(res.into())
+}
+
+fn http_error(e: hyper::Error) -> ManagerError {
+    ManagerError {
+        kind: ManagerErrorKind::HTTPError,
+        msg: e.to_string(),
+    }
+}
@@ -1,17 +1,45 @@
 use crate::err::ManagerError;
 use crate::err::ManagerErrorKind::ChannelError;
-use crate::jobs::types::{Delete, Get, JobMessage, List, StateUpdate, Update};
-use crate::types::{JobInfo, State};
+use crate::jobs::progress::Progress;
+use crate::jobs::types::{
+    Delete, Get, JobMessage, List, OrderUpdate, RateLimitUpdate, SegmentsUpdate, StateUpdate,
+    Update,
+};
+use crate::types::{JobInfo, Segment, State};
 
+#[derive(Clone)]
 pub struct StateClient {
     ch: async_channel::Sender<JobMessage>,
+    progress: Progress,
 }
 
 impl StateClient {
-    pub fn new(ch: async_channel::Sender<JobMessage>) -> Self {
-        StateClient { ch }
+    pub fn new(ch: async_channel::Sender<JobMessage>, progress: Progress) -> Self {
+        StateClient { ch, progress }
+    }
+    // the run of a worker tags its reports, see Progress::start
+    pub fn start(&self, name: &str) -> u64 {
+        self.progress.start(name)
+    }
+    // keeps the progress of a running download in memory, the state daemon writes it later.
+    // state changes go through update so they are written right away
+    pub fn report(&self, job_state: JobInfo, run: u64) {
+        if self.progress.report(job_state, run) {
+            // fails only if the state daemon is gone
+            let _ = self.ch.try_send(JobMessage::Flush);
+        }
+    }
+    pub fn report_segment(&self, name: &str, run: u64, segment: Segment) {
+        self.progress.report_segment(name, run, segment);
+    }
+    // drops the progress of a download that stopped without a state change of its own
+    pub fn discard(&self, name: &str, run: u64) {
+        self.progress.discard(name, run);
     }
     pub async fn get(&self, name: &str) -> Result<JobInfo, ManagerError> {
+        if let Some(state) = self.progress.get(name) {
+            return Ok(state);
+        }
         let (s, r) = async_channel::unbounded();
         self.ch
             .send(JobMessage::Get(Get {
@@ -104,6 +132,60 @@ impl StateClient {
             msg: "couldn't get the response from the state daemon".into(),
         })
     }
+    pub async fn update_rate_limit(&self, name: &str, rate_limit: u64) -> Result<(), ManagerError> {
+        let (s, r) = async_channel::unbounded();
+        self.ch
+            .send(JobMessage::UpdateRateLimit(RateLimitUpdate {
+                name: name.into(),
+                rate_limit,
+                response_channel: s,
+            }))
+            .await?;
+        if let Ok(resp) = r.recv().await {
+            return match resp {
+                JobMessage::Error(e) => Err(e),
+                JobMessage::Ack => Ok(()),
+                _ => Err(ManagerError {
+                    kind: ChannelError,
+                    msg: format!("expected an ack from the state daemon, got {:?}", resp),
+                }),
+            };
+        }
+        Err(ManagerError {
+            kind: ChannelError,
+            msg: "couldn't get the response from the state daemon".into(),
+        })
+    }
+    pub async fn update_order(
+        &self,
+        name: &str,
+        priority: i32,
+        position: i64,
+    ) -> Result<(), ManagerError> {
+        let (s, r) = async_channel::unbounded();
+        self.ch
+            .send(JobMessage::UpdateOrder(OrderUpdate {
+                name: name.into(),
+                priority,
+                position,
+                response_channel: s,
+            }))
+            .await?;
+        if let Ok(resp) = r.recv().await {
+            return match resp {
+                JobMessage::Error(e) => Err(e),
+                JobMessage::Ack => Ok(()),
+                _ => Err(ManagerError {
+                    kind: ChannelError,
+                    msg: format!("expected an ack from the state daemon, got {:?}", resp),
+                }),
+            };
+        }
+        Err(ManagerError {
+            kind: ChannelError,
+            msg: "couldn't get the response from the state daemon".into(),
+        })
+    }
     pub async fn list(&self) -> Result<Vec<JobInfo>, ManagerError> {
         let (s, r) = async_channel::unbounded();
         self.ch
@@ -114,7 +196,10 @@ impl StateClient {
         if let Ok(resp) = r.recv().await {
             return match resp {
                 JobMessage::Error(e) => Err(e),
-                JobMessage::ListResponse(r) => Ok(r),
+                JobMessage::ListResponse(r) => Ok(r
+                    .into_iter()
+                    .map(|state| self.progress.get(&state.name).unwrap_or(state))
+                    .collect()),
                 _ => Err(ManagerError {
                     kind: ChannelError,
                     msg: format!(
@@ -129,4 +214,88 @@ impl StateClient {
             msg: "couldn't get the response from the state daemon".into(),
         })
     }
+    pub async fn get_segments(&self, name: &str) -> Result<Vec<Segment>, ManagerError> {
+        let (s, r) = async_channel::unbounded();
+        self.ch
+            .send(JobMessage::GetSegments(Get {
+                name: name.into(),
+                response_channel: s,
+            }))
+            .await?;
+        if let Ok(resp) = r.recv().await {
+            return match resp {
+                JobMessage::Error(e) => Err(e),
+                JobMessage::SegmentsResponse(mut r) => {
+                    for segment in self.progress.segments(name) {
+                        if let Some(s) = r.iter_mut().find(|s| s.idx == segment.idx) {
+                            *s = segment;
+                        }
+                    }
+                    Ok(r)
+                }
+                _ => Err(ManagerError {
+                    kind: ChannelError,
+                    msg: format!(
+                        "expected a list of segments from the state daemon, got {:?}",
+                        resp
+                    ),
+                }),
+            };
+        }
+        Err(ManagerError {
+            kind: ChannelError,
+            msg: "couldn't get the response from the state daemon".into(),
+        })
+    }
+    pub async fn update_segments(
+        &self,
+        name: &str,
+        segments: Vec<Segment>,
+    ) -> Result<(), ManagerError> {
+        let (s, r) = async_channel::unbounded();
+        self.ch
+            .send(JobMessage::UpdateSegments(SegmentsUpdate {
+                name: name.into(),
+                segments,
+                response_channel: s,
+            }))
+            .await?;
+        if let Ok(resp) = r.recv().await {
+            return match resp {
+                JobMessage::Error(e) => Err(e),
+                JobMessage::Ack => Ok(()),
+                _ => Err(ManagerError {
+                    kind: ChannelError,
+                    msg: format!("expected an ack from the state daemon, got {:?}", resp),
+                }),
+            };
+        }
+        Err(ManagerError {
+            kind: ChannelError,
+            msg: "couldn't get the response from the state daemon".into(),
+        })
+    }
+    pub async fn delete_segments(&self, name: &str) -> Result<(), ManagerError> {
+        let (s, r) = async_channel::unbounded();
+        self.ch
+            .send(JobMessage::DeleteSegments(Delete {
+                name: name.into(),
+                response_channel: s,
+            }))
+            .await?;
+        if let Ok(resp) = r.recv().await {
+            return match resp {
+                JobMessage::Error(e) => Err(e),
+                JobMessage::Ack => Ok(()),
+                _ => Err(ManagerError {
+                    kind: ChannelError,
+                    msg: format!("expected an ack from the state daemon, got {:?}", resp),
+                }),
+            };
+        }
+        Err(ManagerError {
+            kind: ChannelError,
+            msg: "couldn't get the response from the state daemon".into(),
+        })
+    }
 }
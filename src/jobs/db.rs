@@ -1,7 +1,9 @@
+use super::migrations;
 use crate::err::{ManagerError, ManagerErrorKind};
-use crate::types::{JobInfo, State};
-use rusqlite::Connection;
+use crate::types::{JobInfo, RequestOptions, Segment, State};
+use rusqlite::{params, Connection, OptionalExtension};
 use std::str;
+use url::Url;
 pub struct Database {
     conn: Connection,
 }
@@ -16,6 +18,7 @@ impl rusqlite::types::FromSql for State {
                 {
                     "Active" => Ok(Self::Active),
                     "Pending" => Ok(Self::Pending),
+                    "Paused" => Ok(Self::Paused),
                     "Failed" => Ok(Self::Failed),
                     "Cancelled" => Ok(Self::Cancelled),
                     "Done" => Ok(Self::Done),
@@ -26,39 +29,139 @@ impl rusqlite::types::FromSql for State {
         }
     }
 }
-impl Database {
-    pub fn new(path: &str) -> Result<Self, ManagerError> {
-        let conn = Connection::open(path)?;
+const JOB_COLUMNS: &str =
+    "name, url, path, downloaded, total, state, msg, segments, checksum, attempts, max_attempts, next_retry, rate_limit, speed, started_at, finished_at, priority, position, start_at, etag, last_modified, mirrors, variant, headers, cookies, user_agent, referer, proxy, used_proxy, cookies_txt";
+
+fn job_from_row(row: &rusqlite::Row) -> rusqlite::Result<JobInfo> {
+    Ok(JobInfo {
+        name: row.get(0)?,
+        url: row.get(1)?,
+        path: row.get(2)?,
+        downloaded: row.get(3)?,
+        total: row.get(4)?,
+        state: row.get(5)?,
+        msg: row.get(6)?,
+        segments: row.get(7)?,
+        checksum: row.get(8)?,
+        attempts: row.get(9)?,
+        max_attempts: row.get(10)?,
+        next_retry: row.get(11)?,
+        rate_limit: row.get(12)?,
+        speed: row.get(13)?,
+        started_at: row.get(14)?,
+        finished_at: row.get(15)?,
+        priority: row.get(16)?,
+        position: row.get(17)?,
+        start_at: row.get(18)?,
+        etag: row.get(19)?,
+        last_modified: row.get(20)?,
+        mirrors: lines(row.get(21)?),
+        variant: row.get(22)?,
+        request: RequestOptions {
+            headers: lines(row.get(23)?),
+            cookies: lines(row.get(24)?),
+            user_agent: row.get(25)?,
+            referer: row.get(26)?,
+            proxy: row.get(27)?,
+        },
+        used_proxy: row.get(28)?,
+        cookies_txt: row.get(29)?,
+    })
+}
+
+// lists are kept one item per line
+fn lines(v: String) -> Vec<String> {
+    v.lines().map(|l| l.to_string()).collect()
+}
+
+fn write_job(conn: &Connection, state: &JobInfo) -> rusqlite::Result<()> {
+    conn.execute(
+        &format!(
+            "INSERT OR REPLACE INTO jobs ({})
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25, ?26, ?27, ?28, ?29, ?30)",
+            JOB_COLUMNS
+        ),
+        params![
+            state.name,
+            state.url,
+            state.path,
+            state.downloaded.to_string(),
+            state.total.to_string(),
+            state.state.to_string(),
+            state.msg,
+            state.segments.to_string(),
+            state.checksum,
+            state.attempts.to_string(),
+            state.max_attempts.to_string(),
+            state.next_retry.to_string(),
+            state.rate_limit.to_string(),
+            state.speed.to_string(),
+            state.started_at.to_string(),
+            state.finished_at.to_string(),
+            state.priority.to_string(),
+            state.position.to_string(),
+            state.start_at.to_string(),
+            state.etag,
+            state.last_modified,
+            state.mirrors.join("\n"),
+            state.variant,
+            state.request.headers.join("\n"),
+            state.request.cookies.join("\n"),
+            state.request.user_agent,
+            state.request.referer,
+            without_password(&state.request.proxy),
+            state.used_proxy,
+            state.cookies_txt,
+        ],
+    )?;
+    Ok(())
+}
+
+// the password of a job's own proxy is only kept in memory by the daemon
+fn without_password(proxy: &str) -> String {
+    match Url::parse(proxy) {
+        Ok(mut url) if url.password().is_some() => {
+            let _ = url.set_password(None);
+            url.to_string().trim_end_matches('/').to_string()
+        }
+        _ => proxy.into(),
+    }
+}
+
+fn write_segments(conn: &Connection, name: &str, segments: &[Segment]) -> rusqlite::Result<()> {
+    for segment in segments.iter() {
         conn.execute(
-            "create table if not exists jobs (
-                 name text primary key,
-                 url text not null,
-                 path text not null,
-                 downloaded integer,
-                 total integer,
-                 state text,
-                 msg text
-             )",
-            [],
+            "INSERT OR REPLACE INTO segments (name, idx, start, end, downloaded, hash)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                name,
+                segment.idx,
+                segment.start,
+                segment.end,
+                segment.downloaded,
+                segment.hash
+            ],
         )?;
+    }
+    Ok(())
+}
 
+fn job_state(conn: &Connection, name: &str) -> rusqlite::Result<Option<State>> {
+    conn.query_row("SELECT state FROM jobs WHERE name = ?1", [name], |row| {
+        row.get(0)
+    })
+    .optional()
+}
+
+impl Database {
+    pub fn new(path: &str) -> Result<Self, ManagerError> {
+        let mut conn = Connection::open(path)?;
+        migrations::migrate(&mut conn)?;
         Ok(Database { conn })
     }
 
     pub fn update_state(&self, state: JobInfo) -> Result<(), ManagerError> {
-        self.conn.execute(
-            "INSERT OR REPLACE INTO jobs (name, url, path, downloaded, total, state, msg)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
-            [
-                state.name,
-                state.url,
-                state.path,
-                state.downloaded.to_string(),
-                state.total.to_string(),
-                state.state.to_string(),
-                state.msg,
-            ],
-        )?;
+        write_job(&self.conn, &state)?;
         Ok(())
     }
     pub fn update_job_state(&self, name: &str, state: State) -> Result<(), ManagerError> {
@@ -68,29 +171,39 @@ impl Database {
         )?;
         Ok(())
     }
+    pub fn update_rate_limit(&self, name: &str, rate_limit: u64) -> Result<(), ManagerError> {
+        self.conn.execute(
+            "UPDATE jobs SET rate_limit = ?2 WHERE name = ?1",
+            params![name, rate_limit],
+        )?;
+        Ok(())
+    }
+    pub fn update_order(
+        &self,
+        name: &str,
+        priority: i32,
+        position: i64,
+    ) -> Result<(), ManagerError> {
+        self.conn.execute(
+            "UPDATE jobs SET priority = ?2, position = ?3 WHERE name = ?1",
+            params![name, priority, position],
+        )?;
+        Ok(())
+    }
 
     pub fn delete_job(&self, name: &str) -> Result<(), ManagerError> {
         self.conn
             .execute("DELETE FROM jobs WHERE name=?1", [name])?;
+        self.delete_segments(name)?;
         Ok(())
     }
 
     pub fn get_job(&self, name: &str) -> Result<JobInfo, ManagerError> {
-        let mut stmt = self.conn.prepare(
-            "SELECT name, url, path, downloaded, total, state, msg FROM jobs WHERE name = ?1",
-        )?;
+        let mut stmt = self
+            .conn
+            .prepare(&format!("SELECT {} FROM jobs WHERE name = ?1", JOB_COLUMNS))?;
 
-        let mut jobs = stmt.query_map([name], |row| {
-            Ok(JobInfo {
-                name: row.get(0)?,
-                url: row.get(1)?,
-                path: row.get(2)?,
-                downloaded: row.get(3)?,
-                total: row.get(4)?,
-                state: row.get(5)?,
-                msg: row.get(6)?,
-            })
-        })?;
+        let mut jobs = stmt.query_map([name], job_from_row)?;
         if let Some(job) = jobs.next() {
             return Ok(job?);
         }
@@ -102,23 +215,76 @@ impl Database {
     pub fn list_jobs(&self) -> Result<Vec<JobInfo>, ManagerError> {
         let mut stmt = self
             .conn
-            .prepare("SELECT name, url, path, downloaded, total, state, msg FROM jobs")?;
+            .prepare(&format!("SELECT {} FROM jobs", JOB_COLUMNS))?;
+
+        let jobs = stmt.query_map([], job_from_row)?;
+        let mut vs = Vec::new();
+        for job in jobs {
+            vs.push(job?);
+        }
+        Ok(vs)
+    }
+
+    pub fn get_segments(&self, name: &str) -> Result<Vec<Segment>, ManagerError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT idx, start, end, downloaded, hash FROM segments WHERE name = ?1 ORDER BY idx",
+        )?;
 
-        let jobs = stmt.query_map([], |row| {
-            Ok(JobInfo {
-                name: row.get(0)?,
-                url: row.get(1)?,
-                path: row.get(2)?,
+        let segments = stmt.query_map([name], |row| {
+            Ok(Segment {
+                idx: row.get(0)?,
+                start: row.get(1)?,
+                end: row.get(2)?,
                 downloaded: row.get(3)?,
-                total: row.get(4)?,
-                state: row.get(5)?,
-                msg: row.get(6)?,
+                hash: row.get(4)?,
             })
         })?;
         let mut vs = Vec::new();
-        for job in jobs {
-            vs.push(job?);
+        for segment in segments {
+            vs.push(segment?);
         }
         Ok(vs)
     }
+    pub fn update_segments(
+        &mut self,
+        name: &str,
+        segments: Vec<Segment>,
+    ) -> Result<(), ManagerError> {
+        let tx = self.conn.transaction()?;
+        write_segments(&tx, name, &segments)?;
+        tx.commit()?;
+        Ok(())
+    }
+    // writes the progress of several jobs in a single transaction. only jobs that are still
+    // pending or active are written, a late report never undoes a pause, a cancel or a finished
+    // download, nor brings back a forgotten one
+    pub fn flush(
+        &mut self,
+        jobs: Vec<JobInfo>,
+        segments: Vec<(String, Vec<Segment>)>,
+    ) -> Result<(), ManagerError> {
+        let tx = self.conn.transaction()?;
+        for job in jobs.iter() {
+            if !matches!(
+                job_state(&tx, &job.name)?,
+                Some(State::Pending | State::Active)
+            ) {
+                continue;
+            }
+            write_job(&tx, job)?;
+        }
+        for (name, segments) in segments.iter() {
+            if job_state(&tx, name)?.is_none() {
+                continue;
+            }
+            write_segments(&tx, name, segments)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+    pub fn delete_segments(&self, name: &str) -> Result<(), ManagerError> {
+        self.conn
+            .execute("DELETE FROM segments WHERE name=?1", [name])?;
+        Ok(())
+    }
 }
@@ -0,0 +1,257 @@
+use crate::err::{ManagerError, ManagerErrorKind};
+use rusqlite::{Connection, OptionalExtension};
+
+type Migration = fn(&Connection) -> rusqlite::Result<()>;
+
+// the schema version is the number of steps applied, steps only get appended and are never
+// changed once released. databases from before the versioning already have some of the
+// columns, so the steps have to tolerate that
+const MIGRATIONS: [Migration; 14] = [
+    create_jobs,
+    add_segments,
+    add_checksum,
+    add_retries,
+    add_rate_limit,
+    add_timing,
+    add_queue_order,
+    add_start_at,
+    add_validators,
+    add_mirrors,
+    add_variant,
+    add_request_options,
+    add_proxy,
+    add_job_cookies,
+];
+
+// brings the database up to the latest schema, each step in a transaction of its own
+pub fn migrate(conn: &mut Connection) -> Result<(), ManagerError> {
+    conn.execute(
+        "create table if not exists schema_version (version integer not null)",
+        [],
+    )?;
+    let current = version(conn)?;
+    if current > MIGRATIONS.len() {
+        return Err(ManagerError {
+            kind: ManagerErrorKind::DatabaseError,
+            msg: format!(
+                "the database schema version {} is newer than the supported {}",
+                current,
+                MIGRATIONS.len()
+            ),
+        });
+    }
+    for (i, step) in MIGRATIONS.iter().enumerate().skip(current) {
+        let tx = conn.transaction()?;
+        step(&tx)?;
+        tx.execute("delete from schema_version", [])?;
+        tx.execute("insert into schema_version (version) values (?1)", [i + 1])?;
+        tx.commit()?;
+        println!("migrated the database to version {}", i + 1);
+    }
+    Ok(())
+}
+
+fn version(conn: &Connection) -> Result<usize, ManagerError> {
+    let version: Option<i64> = conn
+        .query_row("select version from schema_version", [], |row| row.get(0))
+        .optional()?;
+    Ok(version.unwrap_or(0) as usize)
+}
+
+fn add_column(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = stmt.query_map([], |row| row.get::<_, String>(1))?;
+    for c in columns {
+        if c? == column {
+            return Ok(());
+        }
+    }
+    conn.execute(
+        &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+        [],
+    )?;
+    Ok(())
+}
+
+fn create_jobs(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "create table if not exists jobs (
+             name text primary key,
+             url text not null,
+             path text not null,
+             downloaded integer,
+             total integer,
+             state text,
+             msg text
+         )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn add_segments(conn: &Connection) -> rusqlite::Result<()> {
+    add_column(conn, "jobs", "segments", "integer not null default 1")?;
+    conn.execute(
+        "create table if not exists segments (
+             name text not null,
+             idx integer not null,
+             start integer not null,
+             end integer not null,
+             downloaded integer not null,
+             primary key (name, idx)
+         )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn add_checksum(conn: &Connection) -> rusqlite::Result<()> {
+    add_column(conn, "jobs", "checksum", "text not null default ''")
+}
+
+fn add_retries(conn: &Connection) -> rusqlite::Result<()> {
+    add_column(conn, "jobs", "attempts", "integer not null default 0")?;
+    add_column(conn, "jobs", "max_attempts", "integer not null default 1")?;
+    add_column(conn, "jobs", "next_retry", "integer not null default 0")
+}
+
+fn add_rate_limit(conn: &Connection) -> rusqlite::Result<()> {
+    add_column(conn, "jobs", "rate_limit", "integer not null default 0")
+}
+
+fn add_timing(conn: &Connection) -> rusqlite::Result<()> {
+    add_column(conn, "jobs", "speed", "integer not null default 0")?;
+    add_column(conn, "jobs", "started_at", "integer not null default 0")?;
+    add_column(conn, "jobs", "finished_at", "integer not null default 0")
+}
+
+fn add_queue_order(conn: &Connection) -> rusqlite::Result<()> {
+    add_column(conn, "jobs", "priority", "integer not null default 0")?;
+    add_column(conn, "jobs", "position", "integer not null default 0")?;
+    // jobs added before keep the order they were added in
+    conn.execute("UPDATE jobs SET position = rowid WHERE position = 0", [])?;
+    Ok(())
+}
+
+fn add_start_at(conn: &Connection) -> rusqlite::Result<()> {
+    add_column(conn, "jobs", "start_at", "integer not null default 0")
+}
+
+fn add_validators(conn: &Connection) -> rusqlite::Result<()> {
+    add_column(conn, "jobs", "etag", "text not null default ''")?;
+    add_column(conn, "jobs", "last_modified", "text not null default ''")
+}
+
+fn add_mirrors(conn: &Connection) -> rusqlite::Result<()> {
+    add_column(conn, "jobs", "mirrors", "text not null default ''")?;
+    add_column(conn, "segments", "hash", "text not null default ''")
+}
+
+fn add_variant(conn: &Connection) -> rusqlite::Result<()> {
+    add_column(conn, "jobs", "variant", "text not null default ''")
+}
+
+fn add_request_options(conn: &Connection) -> rusqlite::Result<()> {
+    add_column(conn, "jobs", "headers", "text not null default ''")?;
+    add_column(conn, "jobs", "cookies", "text not null default ''")?;
+    add_column(conn, "jobs", "user_agent", "text not null default ''")?;
+    add_column(conn, "jobs", "referer", "text not null default ''")
+}
+
+fn add_proxy(conn: &Connection) -> rusqlite::Result<()> {
+    add_column(conn, "jobs", "proxy", "text not null default ''")?;
+    add_column(conn, "jobs", "used_proxy", "text not null default ''")
+}
+
+fn add_job_cookies(conn: &Connection) -> rusqlite::Result<()> {
+    add_column(conn, "jobs", "cookies_txt", "text not null default ''")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn columns(conn: &Connection, table: &str) -> Vec<String> {
+        let mut stmt = conn
+            .prepare(&format!("PRAGMA table_info({})", table))
+            .unwrap();
+        let columns = stmt
+            .query_map([], |row| row.get::<_, String>(1))
+            .unwrap()
+            .map(|c| c.unwrap())
+            .collect();
+        columns
+    }
+
+    #[test]
+    fn migrates_a_new_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(version(&conn).unwrap(), MIGRATIONS.len());
+        assert_eq!(columns(&conn, "jobs").len(), 30);
+        assert_eq!(columns(&conn, "segments").len(), 6);
+    }
+
+    #[test]
+    fn migrating_twice_does_nothing() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(version(&conn).unwrap(), MIGRATIONS.len());
+        let versions: i64 = conn
+            .query_row("select count(*) from schema_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(versions, 1);
+    }
+
+    #[test]
+    fn keeps_the_jobs_of_the_first_schema() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        create_jobs(&conn).unwrap();
+        conn.execute(
+            "insert into jobs values ('a', 'http://a', '/a', 10, 20, 'Pending', '')",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "insert into jobs values ('b', 'http://b', '/b', 0, 0, 'Pending', '')",
+            [],
+        )
+        .unwrap();
+        migrate(&mut conn).unwrap();
+        let (downloaded, segments, max_attempts, position): (u64, u32, u32, i64) = conn
+            .query_row(
+                "select downloaded, segments, max_attempts, position from jobs where name = 'b'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!((downloaded, segments, max_attempts, position), (0, 1, 1, 2));
+    }
+
+    #[test]
+    fn upgrades_an_unversioned_database_with_some_columns() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        create_jobs(&conn).unwrap();
+        add_segments(&conn).unwrap();
+        add_checksum(&conn).unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(version(&conn).unwrap(), MIGRATIONS.len());
+        assert_eq!(columns(&conn, "jobs").len(), 30);
+    }
+
+    #[test]
+    fn refuses_a_newer_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        conn.execute("update schema_version set version = version + 1", [])
+            .unwrap();
+        let e = migrate(&mut conn).unwrap_err();
+        assert_eq!(e.kind, ManagerErrorKind::DatabaseError);
+    }
+}
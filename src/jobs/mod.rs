@@ -1,4 +1,6 @@
 pub mod client;
 mod db;
+mod migrations;
+pub mod progress;
 pub mod state;
 pub mod types;
@@ -0,0 +1,301 @@
+use crate::types::{JobInfo, Segment};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// how often the progress is written to the database
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+// bytes downloaded since the last flush that are worth an early one
+const FLUSH_BYTES: u64 = 16 * 1024 * 1024;
+
+#[derive(Default)]
+struct Pending {
+    jobs: HashMap<String, JobInfo>,
+    segments: HashMap<String, BTreeMap<u32, Segment>>,
+    // names changed since the last flush
+    dirty: HashSet<String>,
+    // only the segments that changed are written, metalink downloads can have thousands
+    dirty_segments: HashMap<String, HashSet<u32>>,
+    bytes: u64,
+    // the run whose reports are kept for each job, the ones of a stopped or replaced run are
+    // dropped so that a late report can't bring back the progress of a paused job
+    runs: HashMap<String, u64>,
+    last_run: u64,
+}
+
+// the latest progress of the running downloads, updated on every chunk and written to the
+// database in batches by the state daemon
+#[derive(Clone, Default)]
+pub struct Progress {
+    inner: Arc<Mutex<Pending>>,
+}
+
+impl Progress {
+    // a worker starts running the job, what earlier runs still report is dropped from now on
+    pub fn start(&self, name: &str) -> u64 {
+        let mut p = self.inner.lock().unwrap();
+        p.last_run += 1;
+        let run = p.last_run;
+        p.runs.insert(name.into(), run);
+        run
+    }
+    // returns true if it's worth flushing right away, either because the job just started or
+    // enough was downloaded since the last flush
+    pub fn report(&self, state: JobInfo, run: u64) -> bool {
+        let mut p = self.inner.lock().unwrap();
+        if p.runs.get(&state.name) != Some(&run) {
+            return false;
+        }
+        let prev = p.jobs.get(&state.name).map(|s| s.downloaded);
+        p.bytes += state
+            .downloaded
+            .saturating_sub(prev.unwrap_or(state.downloaded));
+        p.dirty.insert(state.name.clone());
+        p.jobs.insert(state.name.clone(), state);
+        if prev.is_none() || p.bytes >= FLUSH_BYTES {
+            p.bytes = 0;
+            return true;
+        }
+        false
+    }
+    pub fn report_segment(&self, name: &str, run: u64, segment: Segment) {
+        let mut p = self.inner.lock().unwrap();
+        if p.runs.get(name) != Some(&run) {
+            return;
+        }
+        p.dirty_segments
+            .entry(name.into())
+            .or_default()
+            .insert(segment.idx);
+        p.segments
+            .entry(name.into())
+            .or_default()
+            .insert(segment.idx, segment);
+        p.dirty.insert(name.into());
+    }
+    pub fn get(&self, name: &str) -> Option<JobInfo> {
+        self.inner.lock().unwrap().jobs.get(name).cloned()
+    }
+    pub fn segments(&self, name: &str) -> Vec<Segment> {
+        self.inner
+            .lock()
+            .unwrap()
+            .segments
+            .get(name)
+            .map(|s| s.values().cloned().collect())
+            .unwrap_or_default()
+    }
+    pub fn update(&self, name: &str, f: impl FnOnce(&mut JobInfo)) {
+        if let Some(state) = self.inner.lock().unwrap().jobs.get_mut(name) {
+            f(state);
+        }
+    }
+    // takes what changed since the last flush
+    pub fn take_dirty(&self) -> (Vec<JobInfo>, Vec<(String, Vec<Segment>)>) {
+        let mut p = self.inner.lock().unwrap();
+        let dirty = std::mem::take(&mut p.dirty);
+        let dirty_segments = std::mem::take(&mut p.dirty_segments);
+        p.bytes = 0;
+        let jobs = dirty
+            .iter()
+            .filter_map(|n| p.jobs.get(n).cloned())
+            .collect();
+        let segments = dirty_segments
+            .into_iter()
+            .filter_map(|(n, idxs)| {
+                let cached = p.segments.get(&n)?;
+                let changed = idxs.iter().filter_map(|i| cached.get(i).cloned()).collect();
+                Some((n, changed))
+            })
+            .collect();
+        (jobs, segments)
+    }
+    // forgets a job whose state is written directly or that stopped running, its run included
+    pub fn remove(&self, name: &str) {
+        let mut p = self.inner.lock().unwrap();
+        p.jobs.remove(name);
+        p.segments.remove(name);
+        p.dirty.remove(name);
+        p.dirty_segments.remove(name);
+        p.runs.remove(name);
+    }
+    // forgets the progress of a run unless a newer one already started
+    pub fn discard(&self, name: &str, run: u64) {
+        if self.inner.lock().unwrap().runs.get(name) == Some(&run) {
+            self.remove(name);
+        }
+    }
+    pub fn remove_segments(&self, name: &str) {
+        let mut p = self.inner.lock().unwrap();
+        p.segments.remove(name);
+        p.dirty_segments.remove(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jobs::client::StateClient;
+    use crate::jobs::db::Database;
+    use crate::jobs::state::StateDaemon;
+    use crate::types::{RequestOptions, State};
+
+    fn job(name: &str, downloaded: u64) -> JobInfo {
+        JobInfo {
+            name: name.into(),
+            url: format!("http://example.com/{}", name),
+            path: format!("/tmp/{}", name),
+            downloaded,
+            total: 100 * FLUSH_BYTES,
+            state: State::Active,
+            msg: String::new(),
+            segments: 2,
+            checksum: String::new(),
+            attempts: 0,
+            max_attempts: 1,
+            next_retry: 0,
+            rate_limit: 0,
+            speed: 0,
+            started_at: 0,
+            finished_at: 0,
+            priority: 0,
+            position: 1,
+            start_at: 0,
+            etag: String::new(),
+            last_modified: String::new(),
+            mirrors: Vec::new(),
+            variant: String::new(),
+            request: RequestOptions::default(),
+            cookies_txt: String::new(),
+            used_proxy: String::new(),
+        }
+    }
+
+    fn segment(idx: u32, downloaded: u64) -> Segment {
+        Segment {
+            idx,
+            start: idx as u64 * 1000,
+            end: idx as u64 * 1000 + 999,
+            downloaded,
+            hash: String::new(),
+        }
+    }
+
+    fn downloaded(segments: &[Segment]) -> Vec<(u32, u64)> {
+        let mut v: Vec<(u32, u64)> = segments.iter().map(|s| (s.idx, s.downloaded)).collect();
+        v.sort();
+        v
+    }
+
+    #[test]
+    fn coalesces_reports() {
+        let progress = Progress::default();
+        let run = progress.start("a");
+        // the first report of a job is flushed right away
+        assert!(progress.report(job("a", 10), run));
+        assert!(!progress.report(job("a", 20), run));
+        assert!(!progress.report(job("a", 30), run));
+        assert_eq!(progress.get("a").unwrap().downloaded, 30);
+        let (jobs, segments) = progress.take_dirty();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].downloaded, 30);
+        assert!(segments.is_empty());
+        let (jobs, _) = progress.take_dirty();
+        assert!(jobs.is_empty());
+        // still served from memory until the job is forgotten
+        assert_eq!(progress.get("a").unwrap().downloaded, 30);
+        progress.remove("a");
+        assert!(progress.get("a").is_none());
+    }
+
+    #[test]
+    fn flushes_early_after_many_bytes() {
+        let progress = Progress::default();
+        let a = progress.start("a");
+        let b = progress.start("b");
+        assert!(progress.report(job("a", 0), a));
+        assert!(progress.report(job("b", 0), b));
+        assert!(!progress.report(job("a", FLUSH_BYTES / 2), a));
+        // the bytes of all the jobs add up
+        assert!(progress.report(job("b", FLUSH_BYTES / 2), b));
+        assert!(!progress.report(job("a", FLUSH_BYTES / 2 + 1), a));
+        progress.take_dirty();
+        assert!(!progress.report(job("a", FLUSH_BYTES - 1), a));
+    }
+
+    #[test]
+    fn takes_only_the_changed_segments() {
+        let progress = Progress::default();
+        let run = progress.start("a");
+        progress.report_segment("a", run, segment(1, 10));
+        progress.report_segment("a", run, segment(0, 10));
+        progress.report_segment("a", run, segment(1, 20));
+        let (jobs, segments) = progress.take_dirty();
+        assert!(jobs.is_empty());
+        assert_eq!(segments.len(), 1);
+        assert_eq!(downloaded(&segments[0].1), [(0, 10), (1, 20)]);
+        progress.report_segment("a", run, segment(1, 30));
+        let (_, segments) = progress.take_dirty();
+        assert_eq!(downloaded(&segments[0].1), [(1, 30)]);
+        assert_eq!(downloaded(&progress.segments("a")), [(0, 10), (1, 30)]);
+    }
+
+    #[test]
+    fn drops_the_reports_of_stopped_runs() {
+        let progress = Progress::default();
+        let old = progress.start("a");
+        assert!(progress.report(job("a", 10), old));
+        let new = progress.start("a");
+        assert!(!progress.report(job("a", 50), old));
+        progress.report_segment("a", old, segment(0, 50));
+        assert_eq!(progress.get("a").unwrap().downloaded, 10);
+        assert!(progress.segments("a").is_empty());
+        // an old run can't discard the progress of the new one
+        progress.discard("a", old);
+        assert!(progress.get("a").is_some());
+        progress.discard("a", new);
+        assert!(progress.get("a").is_none());
+        // nothing is kept once the job stopped
+        assert!(!progress.report(job("a", 60), new));
+        assert!(progress.get("a").is_none());
+    }
+
+    #[test]
+    fn never_flushes_over_a_stopped_job() {
+        let mut db = Database::new(":memory:").unwrap();
+        db.update_state(job("a", 10)).unwrap();
+        db.update_job_state("a", State::Paused).unwrap();
+        db.flush(
+            vec![job("a", 50)],
+            vec![("gone".into(), vec![segment(0, 50)])],
+        )
+        .unwrap();
+        let a = db.get_job("a").unwrap();
+        assert_eq!((a.state, a.downloaded), (State::Paused, 10));
+        assert!(db.get_segments("gone").unwrap().is_empty());
+        db.update_job_state("a", State::Active).unwrap();
+        db.flush(vec![job("a", 50)], Vec::new()).unwrap();
+        assert_eq!(db.get_job("a").unwrap().downloaded, 50);
+    }
+
+    #[tokio::test]
+    async fn flushes_before_a_state_change() {
+        let (s, r) = async_channel::unbounded();
+        let progress = Progress::default();
+        let daemon = StateDaemon::new(r, ":memory:", progress.clone()).unwrap();
+        tokio::spawn(daemon.work());
+        let client = StateClient::new(s, progress);
+        client.update(job("a", 0)).await.unwrap();
+        let run = client.start("a");
+        client.report_segment("a", run, segment(0, 10));
+        client.report_segment("a", run, segment(1, 20));
+        let mut paused = job("a", 30);
+        paused.state = State::Paused;
+        client.update(paused).await.unwrap();
+        assert_eq!(
+            downloaded(&client.get_segments("a").await.unwrap()),
+            [(0, 10), (1, 20)]
+        );
+        assert_eq!(client.get("a").await.unwrap().state, State::Paused);
+    }
+}
@@ -1,24 +1,57 @@
 use super::db::Database;
+use super::progress::{Progress, FLUSH_INTERVAL};
 use crate::err::ManagerError;
 use crate::jobs::types::JobMessage;
+use tokio::time;
 
 pub struct StateDaemon {
     state_receiver: async_channel::Receiver<JobMessage>,
     db: Database,
+    // shared with the state clients, written to the database in batches
+    progress: Progress,
 }
 
 impl StateDaemon {
     pub fn new(
         state_receiver: async_channel::Receiver<JobMessage>,
         db: &str,
+        progress: Progress,
     ) -> Result<Self, ManagerError> {
         let db = Database::new(db)?;
-        Ok(StateDaemon { state_receiver, db })
+        Ok(StateDaemon {
+            state_receiver,
+            db,
+            progress,
+        })
     }
-    pub async fn work(self) {
-        while let Ok(state) = self.state_receiver.recv().await {
+    fn flush(&mut self) {
+        let (jobs, segments) = self.progress.take_dirty();
+        if jobs.is_empty() && segments.is_empty() {
+            return;
+        }
+        if let Err(e) = self.db.flush(jobs, segments) {
+            println!("failed to save the progress of the downloads {}", e)
+        }
+    }
+    pub async fn work(mut self) {
+        let mut ticker = time::interval(FLUSH_INTERVAL);
+        loop {
+            let state = tokio::select! {
+                state = self.state_receiver.recv() => match state {
+                    Ok(state) => state,
+                    Err(_) => break,
+                },
+                _ = ticker.tick() => {
+                    self.flush();
+                    continue;
+                }
+            };
             match state {
                 JobMessage::Update(msg) => {
+                    // the state changed, it's written right away and replaces the cached progress.
+                    // the pending segment offsets are flushed first so they aren't lost
+                    self.flush();
+                    self.progress.remove(&msg.job_state.name);
                     let res = self.db.update_state(msg.job_state);
                     let _ = match res {
                         Err(e) => msg.response_channel.send(JobMessage::Error(e)).await,
@@ -26,13 +59,36 @@ impl StateDaemon {
                     };
                 }
                 JobMessage::UpdateState(msg) => {
+                    self.flush();
+                    self.progress.remove(&msg.name);
                     let res = self.db.update_job_state(&msg.name, msg.state);
                     let _ = match res {
                         Err(e) => msg.response_channel.send(JobMessage::Error(e)).await,
                         Ok(_) => msg.response_channel.send(JobMessage::Ack).await,
                     };
                 }
+                JobMessage::UpdateRateLimit(msg) => {
+                    self.progress
+                        .update(&msg.name, |s| s.rate_limit = msg.rate_limit);
+                    let res = self.db.update_rate_limit(&msg.name, msg.rate_limit);
+                    let _ = match res {
+                        Err(e) => msg.response_channel.send(JobMessage::Error(e)).await,
+                        Ok(_) => msg.response_channel.send(JobMessage::Ack).await,
+                    };
+                }
+                JobMessage::UpdateOrder(msg) => {
+                    self.progress.update(&msg.name, |s| {
+                        s.priority = msg.priority;
+                        s.position = msg.position;
+                    });
+                    let res = self.db.update_order(&msg.name, msg.priority, msg.position);
+                    let _ = match res {
+                        Err(e) => msg.response_channel.send(JobMessage::Error(e)).await,
+                        Ok(_) => msg.response_channel.send(JobMessage::Ack).await,
+                    };
+                }
                 JobMessage::Delete(msg) => {
+                    self.progress.remove(&msg.name);
                     let res = self.db.delete_job(&msg.name);
                     let _ = match res {
                         Err(e) => msg.response_channel.send(JobMessage::Error(e)).await,
@@ -53,11 +109,40 @@ impl StateDaemon {
                         Ok(v) => msg.response_channel.send(JobMessage::ListResponse(v)).await,
                     };
                 }
+                JobMessage::GetSegments(msg) => {
+                    let res = self.db.get_segments(&msg.name);
+                    let _ = match res {
+                        Err(e) => msg.response_channel.send(JobMessage::Error(e)).await,
+                        Ok(v) => {
+                            msg.response_channel
+                                .send(JobMessage::SegmentsResponse(v))
+                                .await
+                        }
+                    };
+                }
+                JobMessage::UpdateSegments(msg) => {
+                    self.progress.remove_segments(&msg.name);
+                    let res = self.db.update_segments(&msg.name, msg.segments);
+                    let _ = match res {
+                        Err(e) => msg.response_channel.send(JobMessage::Error(e)).await,
+                        Ok(_) => msg.response_channel.send(JobMessage::Ack).await,
+                    };
+                }
+                JobMessage::DeleteSegments(msg) => {
+                    self.progress.remove_segments(&msg.name);
+                    let res = self.db.delete_segments(&msg.name);
+                    let _ = match res {
+                        Err(e) => msg.response_channel.send(JobMessage::Error(e)).await,
+                        Ok(_) => msg.response_channel.send(JobMessage::Ack).await,
+                    };
+                }
+                JobMessage::Flush => self.flush(),
                 _ => {
                     println!("state daemon got an unexpected message {:?}", state)
                 }
             }
         }
+        self.flush();
         println!("state daemon exited, oh noooo")
     }
 }
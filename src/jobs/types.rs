@@ -1,5 +1,5 @@
 use crate::err::ManagerError;
-use crate::types::{JobInfo, State};
+use crate::types::{JobInfo, Segment, State};
 
 #[derive(Debug)]
 pub struct Update {
@@ -13,6 +13,25 @@ pub struct StateUpdate {
     pub response_channel: async_channel::Sender<JobMessage>,
 }
 #[derive(Debug)]
+pub struct SegmentsUpdate {
+    pub name: String,
+    pub segments: Vec<Segment>,
+    pub response_channel: async_channel::Sender<JobMessage>,
+}
+#[derive(Debug)]
+pub struct RateLimitUpdate {
+    pub name: String,
+    pub rate_limit: u64,
+    pub response_channel: async_channel::Sender<JobMessage>,
+}
+#[derive(Debug)]
+pub struct OrderUpdate {
+    pub name: String,
+    pub priority: i32,
+    pub position: i64,
+    pub response_channel: async_channel::Sender<JobMessage>,
+}
+#[derive(Debug)]
 pub struct Get {
     pub name: String,
     pub response_channel: async_channel::Sender<JobMessage>,
@@ -32,10 +51,18 @@ pub enum JobMessage {
     Update(Update),
     Delete(Delete),
     UpdateState(StateUpdate),
+    UpdateRateLimit(RateLimitUpdate),
+    UpdateOrder(OrderUpdate),
     Get(Get),
     List(List),
+    GetSegments(Get),
+    UpdateSegments(SegmentsUpdate),
+    DeleteSegments(Delete),
+    // writes the progress kept in memory, doesn't get a response
+    Flush,
     GetResponse(JobInfo),
     ListResponse(Vec<JobInfo>),
+    SegmentsResponse(Vec<Segment>),
     Ack,
     Error(ManagerError),
 }
@@ -0,0 +1,228 @@
+use crate::err::ManagerError;
+use crate::err::ManagerErrorKind::InvalidChecksum;
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::str::FromStr;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    Sha256,
+    Sha1,
+    Md5,
+}
+
+impl Algorithm {
+    fn hex_len(&self) -> usize {
+        match self {
+            Algorithm::Sha256 => 64,
+            Algorithm::Sha1 => 40,
+            Algorithm::Md5 => 32,
+        }
+    }
+    pub fn hasher(&self) -> Hasher {
+        match self {
+            Algorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            Algorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            Algorithm::Md5 => Hasher::Md5(Md5::new()),
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Algorithm::Sha256 => "sha256",
+                Algorithm::Sha1 => "sha1",
+                Algorithm::Md5 => "md5",
+            }
+        )
+    }
+}
+
+// an expected digest in the `<algorithm>:<hex>` form, e.g. sha256:e3b0c442...
+#[derive(Clone, Debug)]
+pub struct Checksum {
+    pub algorithm: Algorithm,
+    pub digest: String,
+}
+
+impl FromStr for Checksum {
+    type Err = ManagerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (algorithm, digest) = s.split_once(':').ok_or_else(|| ManagerError {
+            kind: InvalidChecksum,
+            msg: format!("{} should be in the form <algorithm>:<hex digest>", s),
+        })?;
+        let algorithm = match algorithm.to_lowercase().as_str() {
+            "sha256" => Algorithm::Sha256,
+            "sha1" => Algorithm::Sha1,
+            "md5" => Algorithm::Md5,
+            _ => {
+                return Err(ManagerError {
+                    kind: InvalidChecksum,
+                    msg: format!(
+                        "unsupported algorithm {}, use sha256, sha1 or md5",
+                        algorithm
+                    ),
+                })
+            }
+        };
+        if digest.len() != algorithm.hex_len() || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(ManagerError {
+                kind: InvalidChecksum,
+                msg: format!(
+                    "{} digest should be {} hex characters",
+                    algorithm,
+                    algorithm.hex_len()
+                ),
+            });
+        }
+        Ok(Checksum {
+            algorithm,
+            digest: digest.to_lowercase(),
+        })
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.digest)
+    }
+}
+
+impl Checksum {
+    // returns an error message if the computed digest doesn't match
+    pub fn verify(&self, hasher: Hasher) -> Option<String> {
+        let digest = hasher.finalize();
+        if digest == self.digest {
+            return None;
+        }
+        Some(format!(
+            "checksum mismatch: expected {}, got {}:{}",
+            self, self.algorithm, digest
+        ))
+    }
+}
+
+pub enum Hasher {
+    Sha256(Sha256),
+    Sha1(Sha1),
+    Md5(Md5),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Sha1(h) => h.update(data),
+            Hasher::Md5(h) => h.update(data),
+        }
+    }
+    // returns the lowercase hex digest
+    pub fn finalize(self) -> String {
+        let digest = match self {
+            Hasher::Sha256(h) => h.finalize().to_vec(),
+            Hasher::Sha1(h) => h.finalize().to_vec(),
+            Hasher::Md5(h) => h.finalize().to_vec(),
+        };
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+// feeds the first `len` bytes of the file at `path` to the hasher
+pub async fn hash_file(path: &Path, hasher: &mut Hasher, len: u64) -> std::io::Result<()> {
+    hash_range(path, hasher, 0, len).await
+}
+
+// feeds `len` bytes of the file at `path` starting at `start` to the hasher
+pub async fn hash_range(
+    path: &Path,
+    hasher: &mut Hasher,
+    start: u64,
+    len: u64,
+) -> std::io::Result<()> {
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(start)).await?;
+    let mut file = file.take(len);
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        hasher.update(&buf[..n]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    fn parses_checksums() {
+        let c: Checksum = format!("SHA256:{}", HELLO_SHA256.to_uppercase())
+            .parse()
+            .unwrap();
+        assert_eq!(c.algorithm, Algorithm::Sha256);
+        assert_eq!(c.digest, HELLO_SHA256);
+        assert_eq!(c.to_string(), format!("sha256:{}", HELLO_SHA256));
+        let c: Checksum = "md5:5d41402abc4b2a76b9719d911017c592".parse().unwrap();
+        assert_eq!(c.algorithm, Algorithm::Md5);
+    }
+
+    #[test]
+    fn refuses_invalid_checksums() {
+        for s in [
+            HELLO_SHA256,
+            "crc32:3610a686",
+            "sha1:5d41402abc4b2a76b9719d911017c592",
+            "md5:5d41402abc4b2a76b9719d911017c59z",
+            "md5:",
+        ] {
+            let e = s.parse::<Checksum>().unwrap_err();
+            assert_eq!(e.kind, InvalidChecksum, "{}", s);
+        }
+    }
+
+    #[test]
+    fn verifies_digests() {
+        let c: Checksum = "sha1:aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d"
+            .parse()
+            .unwrap();
+        let mut hasher = c.algorithm.hasher();
+        hasher.update(b"hel");
+        hasher.update(b"lo");
+        assert_eq!(c.verify(hasher), None);
+        let mut hasher = c.algorithm.hasher();
+        hasher.update(b"hello!");
+        let msg = c.verify(hasher).unwrap();
+        assert!(
+            msg.starts_with("checksum mismatch: expected sha1:aaf4c61d"),
+            "{}",
+            msg
+        );
+    }
+
+    #[tokio::test]
+    async fn hashes_the_start_of_a_file() {
+        let path = std::env::temp_dir().join(format!("downmgr-checksum-{}", std::process::id()));
+        tokio::fs::write(&path, b"hello world").await.unwrap();
+        let mut hasher = Algorithm::Sha256.hasher();
+        let res = hash_file(&path, &mut hasher, 5).await;
+        let _ = tokio::fs::remove_file(&path).await;
+        res.unwrap();
+        assert_eq!(hasher.finalize(), HELLO_SHA256);
+    }
+}
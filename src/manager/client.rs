@@ -2,16 +2,23 @@ use super::stream::ManagerStream;
 use crate::err::ManagerError;
 use crate::err::ManagerErrorKind::{ChannelError, InvalidMessage};
 use crate::manager::types::{
-    AckCommand, AddCommand, CancelCommand, InfoCommand, InfoResponse, ListCommand, ListResponse,
-    Message,
+    AckCommand, AddBatchCommand, AddBatchResponse, AddCommand, CancelCommand, CredentialsCommand,
+    Event, InfoCommand, InfoResponse, LimitCommand, ListCommand, ListResponse, Message,
+    PauseCommand, Reorder, ReorderCommand, ResumeCommand,
 };
 use async_channel::Sender;
+use tokio::sync::broadcast;
 
+#[derive(Clone)]
 pub struct ManagerClient {
     pub ch: Sender<ManagerStream>,
+    pub events: broadcast::Sender<Event>,
 }
 
 impl ManagerClient {
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
     pub async fn list(&self) -> Result<ListResponse, ManagerError> {
         let (job_sender, job_receiver) = async_channel::unbounded();
         self.ch
@@ -90,13 +97,89 @@ impl ManagerClient {
             msg: "couldn't get the response from the daemon".into(),
         })
     }
-    pub async fn add(&self, url: &str, name: Option<&str>) -> Result<AckCommand, ManagerError> {
+    pub async fn pause(&self, name: &str) -> Result<AckCommand, ManagerError> {
+        let (job_sender, job_receiver) = async_channel::unbounded();
+        self.ch
+            .send(ManagerStream::new(
+                Message::Pause(PauseCommand { name: name.into() }),
+                job_sender,
+            ))
+            .await?;
+        if let Ok(msg) = job_receiver.recv().await {
+            return match msg {
+                Message::Ack(r) => Ok(r),
+                Message::Error(e) => Err(e),
+                _ => Err(ManagerError {
+                    kind: InvalidMessage,
+                    msg: format!("expected an ack from the daemon got {:?}", msg),
+                }),
+            };
+        }
+        Err(ManagerError {
+            kind: ChannelError,
+            msg: "couldn't get the response from the daemon".into(),
+        })
+    }
+    pub async fn reorder(&self, name: &str, reorder: Reorder) -> Result<AckCommand, ManagerError> {
+        let (job_sender, job_receiver) = async_channel::unbounded();
+        self.ch
+            .send(ManagerStream::new(
+                Message::Reorder(ReorderCommand {
+                    name: name.into(),
+                    reorder,
+                }),
+                job_sender,
+            ))
+            .await?;
+        if let Ok(msg) = job_receiver.recv().await {
+            return match msg {
+                Message::Ack(r) => Ok(r),
+                Message::Error(e) => Err(e),
+                _ => Err(ManagerError {
+                    kind: InvalidMessage,
+                    msg: format!("expected an ack from the daemon got {:?}", msg),
+                }),
+            };
+        }
+        Err(ManagerError {
+            kind: ChannelError,
+            msg: "couldn't get the response from the daemon".into(),
+        })
+    }
+    pub async fn resume(&self, name: &str) -> Result<AckCommand, ManagerError> {
+        let (job_sender, job_receiver) = async_channel::unbounded();
+        self.ch
+            .send(ManagerStream::new(
+                Message::Resume(ResumeCommand { name: name.into() }),
+                job_sender,
+            ))
+            .await?;
+        if let Ok(msg) = job_receiver.recv().await {
+            return match msg {
+                Message::Ack(r) => Ok(r),
+                Message::Error(e) => Err(e),
+                _ => Err(ManagerError {
+                    kind: InvalidMessage,
+                    msg: format!("expected an ack from the daemon got {:?}", msg),
+                }),
+            };
+        }
+        Err(ManagerError {
+            kind: ChannelError,
+            msg: "couldn't get the response from the daemon".into(),
+        })
+    }
+    pub async fn limit(
+        &self,
+        name: Option<&str>,
+        rate_limit: u64,
+    ) -> Result<AckCommand, ManagerError> {
         let (job_sender, job_receiver) = async_channel::unbounded();
         self.ch
             .send(ManagerStream::new(
-                Message::Add(AddCommand {
-                    url: url.into(),
+                Message::Limit(LimitCommand {
                     name: name.map(|s| s.into()),
+                    rate_limit,
                 }),
                 job_sender,
             ))
@@ -116,4 +199,67 @@ impl ManagerClient {
             msg: "couldn't get the response from the daemon".into(),
         })
     }
+    pub async fn add_credentials(
+        &self,
+        cmd: CredentialsCommand,
+    ) -> Result<AckCommand, ManagerError> {
+        let (job_sender, job_receiver) = async_channel::unbounded();
+        self.ch
+            .send(ManagerStream::new(Message::Credentials(cmd), job_sender))
+            .await?;
+        if let Ok(msg) = job_receiver.recv().await {
+            return match msg {
+                Message::Ack(r) => Ok(r),
+                Message::Error(e) => Err(e),
+                _ => Err(ManagerError {
+                    kind: InvalidMessage,
+                    msg: format!("expected an ack from the daemon got {:?}", msg),
+                }),
+            };
+        }
+        Err(ManagerError {
+            kind: ChannelError,
+            msg: "couldn't get the response from the daemon".into(),
+        })
+    }
+    pub async fn add(&self, cmd: AddCommand) -> Result<AckCommand, ManagerError> {
+        let (job_sender, job_receiver) = async_channel::unbounded();
+        self.ch
+            .send(ManagerStream::new(Message::Add(cmd), job_sender))
+            .await?;
+        if let Ok(msg) = job_receiver.recv().await {
+            return match msg {
+                Message::Ack(r) => Ok(r),
+                Message::Error(e) => Err(e),
+                _ => Err(ManagerError {
+                    kind: InvalidMessage,
+                    msg: format!("expected an ack from the daemon got {:?}", msg),
+                }),
+            };
+        }
+        Err(ManagerError {
+            kind: ChannelError,
+            msg: "couldn't get the response from the daemon".into(),
+        })
+    }
+    pub async fn add_batch(&self, cmd: AddBatchCommand) -> Result<AddBatchResponse, ManagerError> {
+        let (job_sender, job_receiver) = async_channel::unbounded();
+        self.ch
+            .send(ManagerStream::new(Message::AddBatch(cmd), job_sender))
+            .await?;
+        if let Ok(msg) = job_receiver.recv().await {
+            return match msg {
+                Message::AddBatchResponse(r) => Ok(r),
+                Message::Error(e) => Err(e),
+                _ => Err(ManagerError {
+                    kind: InvalidMessage,
+                    msg: format!("expected a batch response from the daemon got {:?}", msg),
+                }),
+            };
+        }
+        Err(ManagerError {
+            kind: ChannelError,
+            msg: "couldn't get the response from the daemon".into(),
+        })
+    }
 }
@@ -0,0 +1,455 @@
+use crate::err::{ManagerError, ManagerErrorKind};
+use crate::types::unix_now;
+use reqwest::cookie::CookieStore;
+use reqwest::header::HeaderValue;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::{Arc, Mutex};
+use url::Url;
+
+// a line of a netscape cookies.txt
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cookie {
+    // without the leading dot
+    pub domain: String,
+    pub include_subdomains: bool,
+    pub path: String,
+    pub secure: bool,
+    pub http_only: bool,
+    // unix timestamp, 0 for a session cookie
+    pub expires: u64,
+    pub name: String,
+    pub value: String,
+}
+
+impl Cookie {
+    fn expired(&self, now: u64) -> bool {
+        self.expires != 0 && self.expires <= now
+    }
+    fn same(&self, other: &Cookie) -> bool {
+        self.domain == other.domain && self.path == other.path && self.name == other.name
+    }
+    fn matches(&self, url: &Url, now: u64) -> bool {
+        let host = url.host_str().unwrap_or_default().to_lowercase();
+        let domain = host == self.domain
+            || (self.include_subdomains && host.ends_with(&format!(".{}", self.domain)));
+        domain
+            && path_matches(url.path(), &self.path)
+            && (!self.secure || url.scheme() == "https")
+            && !self.expired(now)
+    }
+}
+
+// the path is the cookie's or under it
+fn path_matches(path: &str, cookie_path: &str) -> bool {
+    match path.strip_prefix(cookie_path) {
+        Some(rest) => cookie_path.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+// tab separated domain, subdomains, path, secure, expiry, name and value. Comments are skipped
+// except for #HttpOnly_, which curl and browser extensions put in front of the domain
+pub fn parse(text: &str) -> Result<Vec<Cookie>, ManagerError> {
+    let mut cookies = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let (line, http_only) = match line.strip_prefix("#HttpOnly_") {
+            Some(l) => (l, true),
+            None => (line, false),
+        };
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || ManagerError {
+            kind: ManagerErrorKind::InvalidCookies,
+            msg: format!("line {} isn't a netscape cookie", i + 1),
+        };
+        let fields: Vec<&str> = line.trim_end_matches('\r').split('\t').collect();
+        if fields.len() != 7 {
+            return Err(invalid());
+        }
+        cookies.push(Cookie {
+            domain: fields[0].trim_start_matches('.').to_lowercase(),
+            include_subdomains: fields[1].eq_ignore_ascii_case("TRUE"),
+            path: fields[2].into(),
+            secure: fields[3].eq_ignore_ascii_case("TRUE"),
+            http_only,
+            // some exporters write fractions
+            expires: fields[4]
+                .split('.')
+                .next()
+                .unwrap_or_default()
+                .parse()
+                .map_err(|_| invalid())?,
+            name: fields[5].into(),
+            value: fields[6].into(),
+        });
+    }
+    Ok(cookies)
+}
+
+fn format(cookies: &[Cookie]) -> String {
+    let mut text =
+        String::from("# Netscape HTTP Cookie File\n# kept by downmgr, edits are overwritten\n\n");
+    let flag = |b: bool| if b { "TRUE" } else { "FALSE" };
+    for c in cookies.iter() {
+        text.push_str(&format!(
+            "{}{}{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            if c.http_only { "#HttpOnly_" } else { "" },
+            if c.include_subdomains { "." } else { "" },
+            c.domain,
+            flag(c.include_subdomains),
+            c.path,
+            flag(c.secure),
+            c.expires,
+            c.name,
+            c.value
+        ));
+    }
+    text
+}
+
+// parses a Set-Cookie header received from the url, None if it's invalid or for another site
+fn parse_set_cookie(header: &str, url: &Url, now: u64) -> Option<Cookie> {
+    let host = url.host_str()?.to_lowercase();
+    let mut parts = header.split(';');
+    let (name, value) = parts.next()?.split_once('=')?;
+    let mut cookie = Cookie {
+        domain: host.clone(),
+        include_subdomains: false,
+        // the directory of the url by default
+        path: match url.path().rfind('/') {
+            Some(0) | None => "/".into(),
+            Some(i) => url.path()[..i].into(),
+        },
+        secure: false,
+        http_only: false,
+        expires: 0,
+        name: name.trim().into(),
+        value: value.trim().trim_matches('"').into(),
+    };
+    if cookie.name.is_empty() {
+        return None;
+    }
+    let mut max_age = None;
+    for attr in parts {
+        let (key, value) = attr.split_once('=').unwrap_or((attr, ""));
+        let value = value.trim();
+        match key.trim().to_lowercase().as_str() {
+            "domain" if !value.is_empty() => {
+                let domain = value.trim_start_matches('.').to_lowercase();
+                // a site can only set cookies for itself and its parent domains
+                if host != domain && !host.ends_with(&format!(".{}", domain)) {
+                    return None;
+                }
+                // nor for a whole top level domain like com, only for its own host then
+                if !domain.contains('.') {
+                    if host != domain {
+                        return None;
+                    }
+                    continue;
+                }
+                cookie.domain = domain;
+                cookie.include_subdomains = true;
+            }
+            "path" if value.starts_with('/') => cookie.path = value.into(),
+            "secure" => cookie.secure = true,
+            "httponly" => cookie.http_only = true,
+            "max-age" => max_age = value.parse::<i64>().ok(),
+            "expires" => {
+                // Wed, 21-Oct-2015 07:28:00 GMT is common too
+                if let Ok(at) = httpdate::parse_http_date(&value.replace('-', " ")) {
+                    cookie.expires = at
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map(|d| d.as_secs().max(1))
+                        .unwrap_or(1);
+                }
+            }
+            _ => {}
+        }
+    }
+    // takes precedence over expires, 0 or less removes the cookie
+    if let Some(age) = max_age {
+        cookie.expires = if age > 0 { now + age as u64 } else { 1 };
+    }
+    Some(cookie)
+}
+
+// the cookies of the daemon, saved to the jar file whenever a site changes them
+pub struct CookieJar {
+    // kept in memory only if empty
+    path: String,
+    cookies: Mutex<Vec<Cookie>>,
+}
+
+impl CookieJar {
+    // a missing file is an empty jar
+    pub fn open(path: &str) -> Result<Self, ManagerError> {
+        let cookies = if path.is_empty() {
+            Vec::new()
+        } else {
+            match std::fs::read_to_string(path) {
+                Ok(text) => parse(&text).map_err(|e| ManagerError {
+                    kind: e.kind,
+                    msg: format!("{}: {}", path, e.msg),
+                })?,
+                Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(e.into()),
+            }
+        };
+        Ok(CookieJar {
+            path: path.into(),
+            cookies: Mutex::new(cookies),
+        })
+    }
+    // adds the cookies of a cookies.txt, replacing the ones with the same domain, path and name
+    pub fn import(&self, text: &str) -> Result<usize, ManagerError> {
+        let imported = parse(text)?;
+        let count = imported.len();
+        let mut cookies = self.cookies.lock().unwrap();
+        for cookie in imported {
+            Self::insert(&mut cookies, cookie);
+        }
+        self.save(&mut cookies);
+        Ok(count)
+    }
+    fn insert(cookies: &mut Vec<Cookie>, cookie: Cookie) {
+        match cookies.iter_mut().find(|c| c.same(&cookie)) {
+            Some(c) => *c = cookie,
+            None => cookies.push(cookie),
+        }
+    }
+    // written to a temporary file first so that a crash doesn't leave half a jar
+    fn save(&self, cookies: &mut Vec<Cookie>) {
+        let now = unix_now();
+        cookies.retain(|c| !c.expired(now));
+        if self.path.is_empty() {
+            return;
+        }
+        let tmp = format!("{}.tmp", self.path);
+        let res = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)
+            .and_then(|mut f| f.write_all(format(cookies).as_bytes()))
+            .and_then(|_| std::fs::rename(&tmp, &self.path));
+        if let Err(e) = res {
+            println!("couldn't save the cookies to {}: {}", self.path, e);
+        }
+    }
+    // a job's own cookies go before the jar's of the same domain, path and name
+    fn header(&self, url: &Url, extra: &[String], own: &[Cookie]) -> Option<HeaderValue> {
+        let now = unix_now();
+        let cookies = self.cookies.lock().unwrap();
+        let mut matching: Vec<&Cookie> = own
+            .iter()
+            .chain(cookies.iter().filter(|c| !own.iter().any(|o| o.same(c))))
+            .filter(|c| c.matches(url, now))
+            .collect();
+        // longer paths first, like browsers do
+        matching.sort_by_key(|c| std::cmp::Reverse(c.path.len()));
+        let pairs: Vec<String> = extra
+            .iter()
+            .cloned()
+            .chain(matching.iter().map(|c| format!("{}={}", c.name, c.value)))
+            .collect();
+        if pairs.is_empty() {
+            return None;
+        }
+        HeaderValue::from_str(&pairs.join("; ")).ok()
+    }
+    fn store(&self, headers: &mut dyn Iterator<Item = &HeaderValue>, url: &Url) {
+        let now = unix_now();
+        let mut cookies = self.cookies.lock().unwrap();
+        let mut changed = false;
+        for header in headers {
+            let cookie = match header
+                .to_str()
+                .ok()
+                .and_then(|h| parse_set_cookie(h, url, now))
+            {
+                Some(c) => c,
+                None => continue,
+            };
+            if cookies.contains(&cookie) {
+                continue;
+            }
+            Self::insert(&mut cookies, cookie);
+            changed = true;
+        }
+        if changed {
+            self.save(&mut cookies);
+        }
+    }
+}
+
+// what a job's requests send, the cookies given to the job and the matching ones of the jar
+pub struct JobCookies {
+    pub jar: Arc<CookieJar>,
+    // name=value
+    pub extra: Vec<String>,
+    // the cookies.txt of the job, kept out of the jar so that other jobs don't send them
+    pub own: Vec<Cookie>,
+}
+
+impl CookieStore for JobCookies {
+    fn set_cookies(&self, cookie_headers: &mut dyn Iterator<Item = &HeaderValue>, url: &Url) {
+        self.jar.store(cookie_headers, url)
+    }
+    fn cookies(&self, url: &Url) -> Option<HeaderValue> {
+        self.jar.header(url, &self.extra, &self.own)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FUTURE: u64 = 4102444800;
+
+    #[test]
+    fn parses_a_cookies_txt() {
+        let cookies = parse(
+            "# Netscape HTTP Cookie File\n\
+             \n\
+             .Example.com\tTRUE\t/\tFALSE\t4102444800\tsession\tabc\n\
+             #HttpOnly_files.example.com\tFALSE\t/dl\tTRUE\t0\ttoken\tx=y\r\n\
+             # a comment\n\
+             example.org\tFALSE\t/\tFALSE\t1700000000.5\told\t1\n",
+        )
+        .unwrap();
+        assert_eq!(cookies.len(), 3);
+        assert_eq!(
+            cookies[0],
+            Cookie {
+                domain: "example.com".into(),
+                include_subdomains: true,
+                path: "/".into(),
+                secure: false,
+                http_only: false,
+                expires: FUTURE,
+                name: "session".into(),
+                value: "abc".into(),
+            }
+        );
+        assert!(cookies[1].http_only);
+        assert!(cookies[1].secure);
+        assert!(!cookies[1].include_subdomains);
+        assert_eq!(cookies[1].expires, 0);
+        assert_eq!(cookies[1].value, "x=y");
+        assert_eq!(cookies[2].expires, 1700000000);
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        let e = parse("example.com\tTRUE\t/\tFALSE\t0\tonly-name\n").unwrap_err();
+        assert_eq!(e.msg, "line 1 isn't a netscape cookie");
+        let e = parse("# ok\nexample.com\tTRUE\t/\tFALSE\tsoon\tname\tvalue\n").unwrap_err();
+        assert_eq!(e.msg, "line 2 isn't a netscape cookie");
+        assert!(parse("example.com TRUE / FALSE 0 name value\n").is_err());
+    }
+
+    #[test]
+    fn matches_the_domain_path_and_secure_flags() {
+        let cookies = parse(
+            ".example.com\tTRUE\t/\tFALSE\t0\tall\t1\n\
+             files.example.com\tFALSE\t/dl\tTRUE\t0\tsecure\t2\n\
+             example.com\tTRUE\t/\tFALSE\t1\texpired\t3\n",
+        )
+        .unwrap();
+        let now = unix_now();
+        let names = |url: &str| -> Vec<&str> {
+            let url = Url::parse(url).unwrap();
+            cookies
+                .iter()
+                .filter(|c| c.matches(&url, now))
+                .map(|c| c.name.as_str())
+                .collect()
+        };
+        assert_eq!(
+            names("https://files.example.com/dl/a.bin"),
+            ["all", "secure"]
+        );
+        // not over plain http, outside the path or on a subdomain
+        assert_eq!(names("http://files.example.com/dl/a.bin"), ["all"]);
+        assert_eq!(names("https://files.example.com/dlx"), ["all"]);
+        assert_eq!(names("https://a.files.example.com/dl/a.bin"), ["all"]);
+        assert!(names("https://example.org/").is_empty());
+        assert!(names("https://notexample.com/").is_empty());
+    }
+
+    #[test]
+    fn drops_expired_cookies_on_save() {
+        let jar = CookieJar::open("").unwrap();
+        let count = jar
+            .import(
+                "example.com\tFALSE\t/\tFALSE\t1\texpired\t1\n\
+                 example.com\tFALSE\t/\tFALSE\t0\tsession\t2\n",
+            )
+            .unwrap();
+        assert_eq!(count, 2);
+        let url = Url::parse("https://example.com/").unwrap();
+        let header = jar.header(&url, &["extra=0".into()], &[]).unwrap();
+        assert_eq!(header, "extra=0; session=2");
+    }
+
+    #[test]
+    fn keeps_set_cookies_for_the_site_only() {
+        let url = Url::parse("https://files.example.com/dl/a.bin").unwrap();
+        let cookie = parse_set_cookie(
+            "id=\"42\"; Domain=.example.com; Path=/; Secure; HttpOnly; Max-Age=60",
+            &url,
+            1000,
+        )
+        .unwrap();
+        assert_eq!(cookie.domain, "example.com");
+        assert!(cookie.include_subdomains && cookie.secure && cookie.http_only);
+        assert_eq!((cookie.value.as_str(), cookie.expires), ("42", 1060));
+        assert_eq!(parse_set_cookie("a=b", &url, 0).unwrap().path, "/dl");
+        assert!(parse_set_cookie("a=b; Domain=evil.com", &url, 0).is_none());
+        assert!(parse_set_cookie("=b", &url, 0).is_none());
+    }
+
+    #[test]
+    fn refuses_top_level_domains() {
+        let url = Url::parse("https://files.example.com/").unwrap();
+        assert!(parse_set_cookie("a=b; Domain=com", &url, 0).is_none());
+        assert!(parse_set_cookie("a=b; Domain=.COM", &url, 0).is_none());
+        // a single label host keeps its cookie to itself
+        let url = Url::parse("http://intranet/").unwrap();
+        let cookie = parse_set_cookie("a=b; Domain=intranet", &url, 0).unwrap();
+        assert_eq!(cookie.domain, "intranet");
+        assert!(!cookie.include_subdomains);
+    }
+
+    #[test]
+    fn keeps_a_jobs_cookies_to_the_job() {
+        let jar = Arc::new(CookieJar::open("").unwrap());
+        jar.import(
+            "example.com	FALSE	/	FALSE	0	shared	1
+example.com	FALSE	/	FALSE	0	id	jar
+",
+        )
+        .unwrap();
+        let job = JobCookies {
+            jar: jar.clone(),
+            extra: Vec::new(),
+            own: parse(
+                "example.com	FALSE	/	FALSE	0	id	job
+",
+            )
+            .unwrap(),
+        };
+        let other = JobCookies {
+            jar,
+            extra: Vec::new(),
+            own: Vec::new(),
+        };
+        let url = Url::parse("https://example.com/a").unwrap();
+        assert_eq!(job.cookies(&url).unwrap(), "id=job; shared=1");
+        assert_eq!(other.cookies(&url).unwrap(), "shared=1; id=jar");
+    }
+}
@@ -0,0 +1,543 @@
+use crate::err::{ManagerError, ManagerErrorKind};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use md5::Md5;
+use rand::{distributions::Alphanumeric, Rng};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::ErrorKind;
+use std::sync::Mutex;
+use url::{Position, Url};
+
+#[derive(Clone, Default, PartialEq)]
+pub struct Credential {
+    pub user: String,
+    pub password: String,
+}
+
+// the password is left out of the logs
+impl fmt::Debug for Credential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:***", self.user)
+    }
+}
+
+// what a server asked for, kept per host so that the next requests answer it right away
+#[derive(Debug)]
+enum Challenge {
+    Basic,
+    Digest {
+        realm: String,
+        nonce: String,
+        opaque: Option<String>,
+        // MD5 or SHA-256, with -sess appended for the session variants
+        algorithm: String,
+        // qop=auth, the older servers don't send a qop
+        qop: bool,
+        // how many times the nonce was used
+        nc: u32,
+    },
+}
+
+impl Challenge {
+    // the strongest of the challenges of a 401 the store can answer
+    fn choose(headers: &HeaderMap) -> Option<Challenge> {
+        let mut basic = None;
+        for value in headers.get_all(WWW_AUTHENTICATE) {
+            for (scheme, params) in parse_challenges(value.to_str().unwrap_or_default()) {
+                match scheme.as_str() {
+                    "digest" => {
+                        if let Some(c) = Self::digest(&params) {
+                            return Some(c);
+                        }
+                    }
+                    "basic" => basic = Some(Challenge::Basic),
+                    _ => {}
+                }
+            }
+        }
+        basic
+    }
+    fn digest(params: &HashMap<String, String>) -> Option<Challenge> {
+        let algorithm = params
+            .get("algorithm")
+            .map(|a| a.to_uppercase())
+            .unwrap_or_else(|| "MD5".into());
+        if !["MD5", "MD5-SESS", "SHA-256", "SHA-256-SESS"].contains(&algorithm.as_str()) {
+            return None;
+        }
+        let qop = match params.get("qop") {
+            Some(qop) => {
+                // auth-int would need the body
+                if !qop
+                    .split(',')
+                    .any(|q| q.trim().eq_ignore_ascii_case("auth"))
+                {
+                    return None;
+                }
+                true
+            }
+            None => false,
+        };
+        Some(Challenge::Digest {
+            realm: params.get("realm").cloned().unwrap_or_default(),
+            nonce: params.get("nonce")?.clone(),
+            opaque: params.get("opaque").cloned(),
+            algorithm,
+            qop,
+            nc: 0,
+        })
+    }
+    fn answer(&mut self, credential: &Credential, method: &str, url: &Url) -> String {
+        let cnonce: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect();
+        self.answer_with(credential, method, url, &cnonce)
+    }
+    fn answer_with(
+        &mut self,
+        credential: &Credential,
+        method: &str,
+        url: &Url,
+        cnonce: &str,
+    ) -> String {
+        let (realm, nonce, opaque, algorithm, qop, nc) = match self {
+            Challenge::Basic => {
+                let pair = format!("{}:{}", credential.user, credential.password);
+                return format!("Basic {}", BASE64.encode(pair));
+            }
+            Challenge::Digest {
+                realm,
+                nonce,
+                opaque,
+                algorithm,
+                qop,
+                nc,
+            } => (realm, nonce, opaque, algorithm, qop, nc),
+        };
+        *nc += 1;
+        let hash = |data: String| -> String {
+            let digest = if algorithm.starts_with("SHA-256") {
+                Sha256::digest(data.as_bytes()).to_vec()
+            } else {
+                Md5::digest(data.as_bytes()).to_vec()
+            };
+            digest.iter().map(|b| format!("{:02x}", b)).collect()
+        };
+        let uri = &url[Position::BeforePath..Position::AfterQuery];
+        let nc = format!("{:08x}", nc);
+        let mut ha1 = hash(format!(
+            "{}:{}:{}",
+            credential.user, realm, credential.password
+        ));
+        if algorithm.ends_with("-SESS") {
+            ha1 = hash(format!("{}:{}:{}", ha1, nonce, cnonce));
+        }
+        let ha2 = hash(format!("{}:{}", method, uri));
+        let response = if *qop {
+            hash(format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc, cnonce, ha2))
+        } else {
+            hash(format!("{}:{}:{}", ha1, nonce, ha2))
+        };
+        let mut header = format!(
+            "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", algorithm={}, response=\"{}\"",
+            quote(&credential.user),
+            quote(realm),
+            quote(nonce),
+            uri,
+            algorithm.replace("-SESS", "-sess"),
+            response
+        );
+        if *qop {
+            header.push_str(&format!(", qop=auth, nc={}, cnonce=\"{}\"", nc, cnonce));
+        }
+        if let Some(opaque) = opaque {
+            header.push_str(&format!(", opaque=\"{}\"", quote(opaque)));
+        }
+        header
+    }
+}
+
+fn quote(v: &str) -> String {
+    v.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// the challenges of a WWW-Authenticate header, a scheme followed by its comma separated
+// parameters, with the names lowercased
+fn parse_challenges(header: &str) -> Vec<(String, HashMap<String, String>)> {
+    let mut challenges: Vec<(String, HashMap<String, String>)> = Vec::new();
+    let mut rest = header;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+        if rest.is_empty() {
+            break;
+        }
+        let end = rest
+            .find(|c: char| c == '=' || c == ',' || c.is_whitespace())
+            .unwrap_or(rest.len());
+        let (token, after) = rest.split_at(end);
+        let value = match after.trim_start().strip_prefix('=') {
+            Some(v) => v.trim_start(),
+            None => {
+                challenges.push((token.to_lowercase(), HashMap::new()));
+                rest = after;
+                continue;
+            }
+        };
+        let (value, after) = match value.strip_prefix('"') {
+            Some(quoted) => {
+                let mut v = String::new();
+                let mut end = quoted.len();
+                let mut escaped = false;
+                for (i, c) in quoted.char_indices() {
+                    match c {
+                        _ if escaped => {
+                            v.push(c);
+                            escaped = false;
+                        }
+                        '\\' => escaped = true,
+                        '"' => {
+                            end = i + 1;
+                            break;
+                        }
+                        _ => v.push(c),
+                    }
+                }
+                (v, &quoted[end..])
+            }
+            None => {
+                let end = value.find(',').unwrap_or(value.len());
+                (value[..end].trim().to_string(), &value[end..])
+            }
+        };
+        // the padding of a token68 has no name
+        if let Some((_, params)) = challenges.last_mut().filter(|_| !token.is_empty()) {
+            params.insert(token.to_lowercase(), value);
+        }
+        rest = after;
+    }
+    challenges
+}
+
+// machine, login and password entries of a .netrc, default applies to the hosts without one.
+// macros are skipped
+pub fn parse_netrc(text: &str) -> Vec<(String, Credential)> {
+    let mut entries = Vec::new();
+    let mut current: Option<(String, Credential)> = None;
+    let mut lines = text.lines();
+    while let Some(line) = lines.next() {
+        let mut tokens = line.split_whitespace();
+        while let Some(token) = tokens.next() {
+            match token {
+                "machine" | "default" => {
+                    entries.extend(current.take());
+                    let host = match token {
+                        "default" => "*".to_string(),
+                        _ => tokens.next().unwrap_or_default().to_lowercase(),
+                    };
+                    current = Some((host, Credential::default()));
+                }
+                "login" | "password" => {
+                    let value = tokens.next().unwrap_or_default().to_string();
+                    if let Some((_, c)) = current.as_mut() {
+                        match token {
+                            "login" => c.user = value,
+                            _ => c.password = value,
+                        }
+                    }
+                }
+                "account" => {
+                    tokens.next();
+                }
+                // runs until an empty line
+                "macdef" => {
+                    for l in lines.by_ref() {
+                        if l.trim().is_empty() {
+                            break;
+                        }
+                    }
+                    break;
+                }
+                t if t.starts_with('#') => break,
+                _ => {}
+            }
+        }
+    }
+    entries.extend(current);
+    entries.retain(|(host, c)| !host.is_empty() && !c.user.is_empty());
+    entries
+}
+
+// reads the .netrc at path, a missing one has no entries
+pub fn read_netrc(path: &str) -> Result<Vec<(String, Credential)>, ManagerError> {
+    if path.is_empty() {
+        return Ok(Vec::new());
+    }
+    match std::fs::read_to_string(path) {
+        Ok(text) => Ok(parse_netrc(&text)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(ManagerError {
+            kind: ManagerErrorKind::IO,
+            msg: format!("couldn't read {}: {}", path, e),
+        }),
+    }
+}
+
+// the credentials of the daemon by host, only sent to the hosts that ask for them
+#[derive(Debug, Default)]
+pub struct Credentials {
+    // from the .netrc and the config, replaced on reload
+    configured: Mutex<HashMap<String, Credential>>,
+    // given over the api, kept in memory only and going before the configured ones
+    added: Mutex<HashMap<String, Credential>>,
+    // the last challenge of each host and port
+    challenges: Mutex<HashMap<String, Challenge>>,
+}
+
+impl Credentials {
+    // the later entries go before the earlier ones
+    pub fn configure(&self, entries: Vec<(String, Credential)>) {
+        let entries = entries
+            .into_iter()
+            .map(|(host, c)| (host.to_lowercase(), c))
+            .collect();
+        *self.configured.lock().unwrap() = entries;
+    }
+    pub fn add(&self, host: &str, credential: Credential) -> Result<(), ManagerError> {
+        let host = host.trim().to_lowercase();
+        if host.is_empty() || host.contains('/') || credential.user.is_empty() {
+            return Err(ManagerError {
+                kind: ManagerErrorKind::InvalidCredentials,
+                msg: "credentials need a host, optionally with a port, and a user".into(),
+            });
+        }
+        self.added.lock().unwrap().insert(host, credential);
+        Ok(())
+    }
+    // host:port is looked up before the host alone
+    fn find(&self, url: &Url) -> Option<Credential> {
+        let host = url.host_str()?.to_lowercase();
+        let keys = [url.port().map(|p| format!("{}:{}", host, p)), Some(host)];
+        let added = self.added.lock().unwrap();
+        let configured = self.configured.lock().unwrap();
+        keys.iter()
+            .flatten()
+            .find_map(|k| added.get(k).or_else(|| configured.get(k)))
+            .or_else(|| configured.get("*"))
+            .cloned()
+    }
+    // keeps the challenge of a 401 if there are credentials to answer it with
+    pub fn challenged(&self, url: &Url, headers: &HeaderMap) -> bool {
+        if self.find(url).is_none() {
+            return false;
+        }
+        match Challenge::choose(headers) {
+            Some(challenge) => {
+                self.challenges
+                    .lock()
+                    .unwrap()
+                    .insert(origin(url), challenge);
+                true
+            }
+            None => false,
+        }
+    }
+    // answers the last challenge of the request's host
+    pub fn authorize(&self, req: &mut reqwest::Request) {
+        let credential = match self.find(req.url()) {
+            Some(c) => c,
+            None => return,
+        };
+        let mut challenges = self.challenges.lock().unwrap();
+        let challenge = match challenges.get_mut(&origin(req.url())) {
+            Some(c) => c,
+            None => return,
+        };
+        let answer = challenge.answer(&credential, req.method().as_str(), req.url());
+        if let Ok(mut v) = HeaderValue::from_str(&answer) {
+            v.set_sensitive(true);
+            req.headers_mut().insert(AUTHORIZATION, v);
+        }
+    }
+}
+
+fn origin(url: &Url) -> String {
+    format!(
+        "{}:{}",
+        url.host_str().unwrap_or_default().to_lowercase(),
+        url.port_or_known_default().unwrap_or_default()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for v in values {
+            headers.append(WWW_AUTHENTICATE, HeaderValue::from_str(v).unwrap());
+        }
+        headers
+    }
+
+    fn param<'a>(header: &'a str, name: &str) -> &'a str {
+        let start = header.find(&format!("{}=", name)).unwrap() + name.len() + 1;
+        let rest = header[start..].trim_start_matches('"');
+        &rest[..rest.find(['"', ',']).unwrap_or(rest.len())]
+    }
+
+    // the example of rfc 7616 section 3.9.1
+    const RFC_CHALLENGE: &str = "Digest realm=\"http-auth@example.org\", qop=\"auth, auth-int\", \
+        algorithm={}, nonce=\"7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v\", \
+        opaque=\"FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS\"";
+    const RFC_CNONCE: &str = "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ";
+
+    fn rfc_answer(algorithm: &str) -> String {
+        let mut challenge =
+            Challenge::choose(&headers(&[&RFC_CHALLENGE.replace("{}", algorithm)])).unwrap();
+        let credential = Credential {
+            user: "Mufasa".into(),
+            password: "Circle of Life".into(),
+        };
+        let url = Url::parse("http://www.example.org/dir/index.html").unwrap();
+        challenge.answer_with(&credential, "GET", &url, RFC_CNONCE)
+    }
+
+    #[test]
+    fn answers_the_rfc_md5_example() {
+        let answer = rfc_answer("MD5");
+        assert_eq!(
+            param(&answer, "response"),
+            "8ca523f5e9506fed4657c9700eebdbec"
+        );
+        assert_eq!(param(&answer, "uri"), "/dir/index.html");
+        assert_eq!(param(&answer, "nc"), "00000001");
+        assert_eq!(param(&answer, "qop"), "auth");
+        assert_eq!(
+            param(&answer, "opaque"),
+            "FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS"
+        );
+    }
+
+    #[test]
+    fn answers_the_rfc_sha256_example() {
+        let answer = rfc_answer("SHA-256");
+        assert_eq!(
+            param(&answer, "response"),
+            "753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1"
+        );
+        assert_eq!(param(&answer, "algorithm"), "SHA-256");
+    }
+
+    #[test]
+    fn counts_the_uses_of_a_nonce() {
+        let mut challenge =
+            Challenge::choose(&headers(&[&RFC_CHALLENGE.replace("{}", "MD5")])).unwrap();
+        let url = Url::parse("http://www.example.org/").unwrap();
+        challenge.answer(&Credential::default(), "GET", &url);
+        let answer = challenge.answer(&Credential::default(), "GET", &url);
+        assert_eq!(param(&answer, "nc"), "00000002");
+    }
+
+    #[test]
+    fn answers_basic() {
+        let mut challenge = Challenge::choose(&headers(&["Basic realm=\"files\""])).unwrap();
+        let credential = Credential {
+            user: "Aladdin".into(),
+            password: "open sesame".into(),
+        };
+        let url = Url::parse("http://example.org/").unwrap();
+        assert_eq!(
+            challenge.answer(&credential, "GET", &url),
+            "Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ=="
+        );
+    }
+
+    #[test]
+    fn parses_quoted_and_escaped_params() {
+        let challenges = parse_challenges("Basic realm=\"say \\\"hi\\\", please\", charset=UTF-8");
+        assert_eq!(challenges.len(), 1);
+        let (scheme, params) = &challenges[0];
+        assert_eq!(scheme, "basic");
+        assert_eq!(params["realm"], "say \"hi\", please");
+        assert_eq!(params["charset"], "UTF-8");
+    }
+
+    #[test]
+    fn parses_many_challenges_in_one_header() {
+        let challenges = parse_challenges(
+            "Newauth realm=\"apps\", type=1, title=\"Login, please\", Basic realm=\"simple\", \
+             Negotiate YWJj==, Digest realm=\"x\", nonce=\"n\"",
+        );
+        let schemes: Vec<&str> = challenges.iter().map(|(s, _)| s.as_str()).collect();
+        assert_eq!(schemes, ["newauth", "basic", "negotiate", "digest"]);
+        assert_eq!(challenges[0].1["title"], "Login, please");
+        assert_eq!(challenges[1].1["realm"], "simple");
+        assert_eq!(challenges[3].1["nonce"], "n");
+    }
+
+    #[test]
+    fn prefers_digest_over_basic() {
+        let both = headers(&["Basic realm=\"a\"", "Digest realm=\"a\", nonce=\"n\""]);
+        assert!(matches!(
+            Challenge::choose(&both),
+            Some(Challenge::Digest { .. })
+        ));
+        // auth-int needs the body, and unknown algorithms can't be answered
+        let unsupported = headers(&[
+            "Digest realm=\"a\", nonce=\"n\", qop=\"auth-int\"",
+            "Digest realm=\"a\", nonce=\"n\", algorithm=SHA-512-256",
+            "Basic realm=\"a\"",
+        ]);
+        assert!(matches!(
+            Challenge::choose(&unsupported),
+            Some(Challenge::Basic)
+        ));
+        assert!(Challenge::choose(&headers(&["Bearer realm=\"a\""])).is_none());
+    }
+
+    #[test]
+    fn parses_netrc() {
+        let entries = parse_netrc(
+            "# personal logins\n\
+             machine Files.Example.com login alice password s3cret\n\
+             machine ftp.example.com\n  login bob\n  account x password hunter2 # old\n\
+             macdef init\n  machine evil.com login eve password x\n\n\
+             machine nologin.example.com password x\n\
+             default login anonymous password me@example.com\n",
+        );
+        let hosts: Vec<&str> = entries.iter().map(|(h, _)| h.as_str()).collect();
+        assert_eq!(hosts, ["files.example.com", "ftp.example.com", "*"]);
+        assert_eq!(entries[0].1.user, "alice");
+        assert_eq!(entries[0].1.password, "s3cret");
+        assert_eq!(entries[1].1.user, "bob");
+        assert_eq!(entries[1].1.password, "hunter2");
+        assert_eq!(entries[2].1.user, "anonymous");
+    }
+
+    #[test]
+    fn finds_the_most_specific_credentials() {
+        let store = Credentials::default();
+        let login = |user: &str| Credential {
+            user: user.into(),
+            password: "".into(),
+        };
+        store.configure(vec![
+            ("example.com".into(), login("host")),
+            ("example.com:8443".into(), login("port")),
+            ("*".into(), login("default")),
+        ]);
+        let find = |url: &str| store.find(&Url::parse(url).unwrap()).unwrap().user;
+        assert_eq!(find("https://example.com/a"), "host");
+        assert_eq!(find("https://EXAMPLE.com:8443/a"), "port");
+        assert_eq!(find("https://other.com/a"), "default");
+        store.add("example.com", login("added")).unwrap();
+        assert_eq!(find("https://example.com/a"), "added");
+        assert!(store.add("", login("x")).is_err());
+    }
+}
@@ -1,61 +1,148 @@
+use super::checksum::Checksum;
+use super::cookies::{self, CookieJar};
+use super::credentials::{self, Credential, Credentials};
+use super::hls::{self, Variant};
+use super::limiter::RateLimiter;
+use super::metalink;
+use super::protocol::Protocol;
+use super::proxy;
+use super::queue::{JobQueue, QueueOrder};
+use super::resume::Validators;
+use super::retry::requeue_after;
 use super::stream::ManagerStream;
-use super::types::{AckCommand, CancelInfo, DownloadJob, InfoResponse, ListResponse, Message};
+use super::types::{
+    part_path, AckCommand, AddBatchCommand, AddBatchResponse, AddCommand, AddResult, CancelInfo,
+    CredentialsCommand, DownloadJob, Event, EventKind, InfoResponse, ListResponse, Message,
+    Reorder,
+};
 use super::worker::DownloadWorker;
+use crate::config::Config;
 use crate::err::ManagerError;
 use crate::err::ManagerErrorKind::{
-    DownloadJobNameAlreadyExist, DownloadJobNotFound, InvalidMessage,
+    DownloadJobNameAlreadyExist, DownloadJobNotFound, InvalidJobState, InvalidMessage,
+    UnsupportedProtocol, IO,
 };
 use crate::jobs::client::StateClient;
+use crate::jobs::progress::Progress;
 use crate::jobs::state::StateDaemon;
-use crate::types::{JobInfo, State};
+use crate::types::{unix_now, JobInfo, RequestOptions, State};
 use async_channel;
+use chrono::Local;
 use std::collections::HashMap;
 use std::io::ErrorKind;
 use std::path::Path;
+use std::sync::Arc;
 use tokio;
-use tokio::sync::watch;
+use tokio::sync::{broadcast, mpsc, watch};
 use tokio::time;
 use url::Url;
 
 use rand::{distributions::Alphanumeric, Rng}; // 0.8
 
+// how often the download windows are checked
+const SCHEDULE_INTERVAL: time::Duration = time::Duration::from_secs(10);
+
 pub struct ManagerDaemon {
     server: async_channel::Receiver<ManagerStream>,
-    job_sender: async_channel::Sender<DownloadJob>,
+    queue: JobQueue,
     state_client: StateClient,
     cancel_channels: HashMap<String, watch::Sender<CancelInfo>>,
-    downloads_path: String,
+    // limiters of the queued and running jobs so they can be changed at runtime
+    job_limiters: HashMap<String, RateLimiter>,
+    global_limiter: RateLimiter,
+    // queue order of the waiting jobs so they can be reordered
+    job_orders: HashMap<String, QueueOrder>,
+    // locked while a worker runs the job, a resumed job waits for the worker of its last run
+    job_running: HashMap<String, Arc<tokio::sync::Mutex<()>>>,
+    // the proxies of the jobs that have a password, which isn't saved with the job
+    job_proxies: HashMap<String, String>,
+    // position given to the next job added
+    next_position: i64,
+    events: broadcast::Sender<Event>,
+    // shared by all the jobs
+    cookies: Arc<CookieJar>,
+    credentials: Arc<Credentials>,
+    config: Config,
+    // new configs to apply when the config file is reloaded
+    reloads: mpsc::UnboundedReceiver<Config>,
 }
 
 impl ManagerDaemon {
-    // TODO: refactor into a config struct
     pub fn new(
-        workers: u32,
+        config: Config,
         listener: async_channel::Receiver<ManagerStream>,
-        db_path: &str,
-        downloads_path: &str,
+        events: broadcast::Sender<Event>,
+        reloads: mpsc::UnboundedReceiver<Config>,
     ) -> Result<Self, ManagerError> {
-        let (job_sender, job_receiver) = async_channel::unbounded();
+        let global_limiter = RateLimiter::new(config.limit);
+        let queue = JobQueue::new();
         let (state_sender, state_receiver) = async_channel::unbounded();
-        for _ in 0..workers {
-            tokio::spawn(DownloadWorker::new(job_receiver.clone(), state_sender.clone()).work());
+        let progress = Progress::default();
+        let state_client = StateClient::new(state_sender, progress.clone());
+        for _ in 0..config.workers {
+            tokio::spawn(
+                DownloadWorker::new(
+                    queue.clone(),
+                    state_client.clone(),
+                    global_limiter.clone(),
+                    events.clone(),
+                )
+                .work(),
+            );
+        }
+        for path in [&config.database, &config.cookie_jar] {
+            if let Some(dir) = Path::new(path).parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+        }
+        if !config.incomplete.is_empty() {
+            std::fs::create_dir_all(&config.incomplete)?;
         }
-        tokio::spawn(StateDaemon::new(state_receiver, db_path)?.work());
+        tokio::spawn(StateDaemon::new(state_receiver, &config.database, progress)?.work());
+        let cookies = Arc::new(CookieJar::open(&config.cookie_jar)?);
+        if !config.cookies.is_empty() {
+            import_cookies(&cookies, &config.cookies)?;
+        }
+        let credentials = Arc::new(Credentials::default());
+        load_credentials(&credentials, &config)?;
         Ok(ManagerDaemon {
             server: listener,
-            job_sender,
-            state_client: StateClient::new(state_sender),
+            queue,
+            state_client,
             cancel_channels: HashMap::new(),
-            downloads_path: downloads_path.into(),
+            job_limiters: HashMap::new(),
+            global_limiter,
+            job_orders: HashMap::new(),
+            job_running: HashMap::new(),
+            job_proxies: HashMap::new(),
+            next_position: 0,
+            events,
+            cookies,
+            credentials,
+            config,
+            reloads,
         })
     }
     pub async fn serve(mut self) -> Result<(), ManagerError> {
-        if let Err(e) = self.push_active_jobs().await {
-            println!("failed to push active jobs {}", e)
+        self.queue
+            .set_open(self.config.schedule.is_open(Local::now()));
+        if let Err(e) = self.push_unfinished_jobs().await {
+            println!("failed to push unfinished jobs {}", e)
         }
+        let mut ticker = time::interval(SCHEDULE_INTERVAL);
         // a single loop handling all the connections
         loop {
-            let stream = self.server.recv().await;
+            let stream = tokio::select! {
+                stream = self.server.recv() => stream,
+                _ = ticker.tick() => {
+                    self.apply_schedule().await;
+                    continue;
+                }
+                Some(config) = self.reloads.recv() => {
+                    self.reload(config).await;
+                    continue;
+                }
+            };
             if let Err(e) = stream {
                 println!("couldn't accept connection {}", e);
                 time::sleep(time::Duration::from_secs(1)).await;
@@ -72,24 +159,12 @@ impl ManagerDaemon {
             self.scrape_cancel_channels().await;
         }
     }
-    async fn push_active_jobs(&mut self) -> Result<(), ManagerError> {
+    async fn push_unfinished_jobs(&mut self) -> Result<(), ManagerError> {
         let states = self.state_client.list().await?;
+        self.next_position = states.iter().map(|s| s.position).max().unwrap_or(0) + 1;
         for state in states.iter() {
-            if state.state == State::Active {
-                let (tx, rx) = watch::channel(CancelInfo {
-                    cancel: false,
-                    delete: false,
-                });
-                let job = DownloadJob {
-                    name: state.name.clone(),
-                    file_path: Path::new(&state.path).to_path_buf(),
-                    url: state.url.to_string(),
-                    cancel_channel: rx,
-                };
-                self.cancel_channels.insert(state.name.clone(), tx);
-                if let Err(e) = self.job_sender.send(job).await {
-                    println!("failed to send to the download channel {}", e);
-                }
+            if state.state == State::Active || state.state == State::Pending {
+                self.enqueue(state);
             }
         }
         Ok(())
@@ -116,6 +191,10 @@ impl ManagerDaemon {
         }
         for name in to_remove.into_iter() {
             self.cancel_channels.remove(&name);
+            self.job_limiters.remove(&name);
+            self.job_orders.remove(&name);
+            self.forget_running(&name);
+            self.job_proxies.remove(&name);
         }
     }
     async fn handle(&mut self, api: &mut ManagerStream) -> Result<Message, ManagerError> {
@@ -123,7 +202,11 @@ impl ManagerDaemon {
         match cmd {
             Message::Add(c) => {
                 println!("adding {}", c.url);
-                Ok(self.add(&c.url, c.name.as_deref()).await?)
+                Ok(self.add(c).await?)
+            }
+            Message::AddBatch(c) => {
+                println!("adding {} jobs", c.jobs.len());
+                Ok(self.add_batch(c).await?)
             }
             Message::Info(c) => {
                 println!("querying {}", c.name);
@@ -133,6 +216,26 @@ impl ManagerDaemon {
                 println!("cancelling");
                 Ok(self.cancel(&c.name, c.forget, c.delete).await?)
             }
+            Message::Pause(c) => {
+                println!("pausing {}", c.name);
+                Ok(self.pause(&c.name).await?)
+            }
+            Message::Resume(c) => {
+                println!("resuming {}", c.name);
+                Ok(self.resume(&c.name).await?)
+            }
+            Message::Limit(c) => {
+                println!("limiting {:?} to {}", c.name, c.rate_limit);
+                Ok(self.limit(c.name.as_deref(), c.rate_limit).await?)
+            }
+            Message::Reorder(c) => {
+                println!("reordering {} {:?}", c.name, c.reorder);
+                Ok(self.reorder(&c.name, c.reorder).await?)
+            }
+            Message::Credentials(c) => {
+                println!("adding the credentials of {}", c.host);
+                Ok(self.add_credentials(c)?)
+            }
             Message::List(_) => {
                 println!("listing");
                 Ok(self.list().await?)
@@ -165,53 +268,181 @@ impl ManagerDaemon {
             .map(char::from)
             .collect()
     }
-    async fn add(&mut self, url: &str, mut name: Option<&str>) -> Result<Message, ManagerError> {
+    async fn add(&mut self, cmd: AddCommand) -> Result<Message, ManagerError> {
+        if let Some(doc) = &cmd.metalink {
+            return self.add_metalink(&cmd, doc).await;
+        }
+        let url = cmd.url.as_str();
+        let mut name = cmd.name.as_deref();
         println!("name: {:?}", name);
         let u = Url::parse(url)?;
+        let protocol = Protocol::of(url)?;
+        // playlists are resolved when the download starts, only the variant is checked here
+        let hls = cmd.hls || cmd.variant.is_some() || hls::is_playlist(url);
+        let variant = if hls {
+            if protocol != Protocol::Http {
+                return Err(ManagerError {
+                    kind: UnsupportedProtocol,
+                    msg: "hls playlists are only fetched over http".into(),
+                });
+            }
+            cmd.variant
+                .as_deref()
+                .unwrap_or("")
+                .parse::<Variant>()?
+                .to_string()
+        } else {
+            "".into()
+        };
+        let checksum = match &cmd.checksum {
+            Some(c) => Some(c.parse::<Checksum>()?),
+            None => None,
+        };
         let segments = u.path_segments();
-        if let Some(segments) = segments {
-            let last = segments.last();
+        if let Some(mut segments) = segments {
+            let last = segments.next_back();
             if name.is_none() && !last.unwrap_or("").is_empty() {
                 name = last;
             }
         }
+        // the segments are joined into a transport stream
+        let stream_name = match name {
+            Some(n) if hls && cmd.name.is_none() => {
+                Some(format!("{}.ts", n.strip_suffix(".m3u8").unwrap_or(n)))
+            }
+            _ => None,
+        };
         let rand_name = Self::random_name().await;
-        let name = name.unwrap_or_else(|| rand_name.as_ref());
+        let name = stream_name
+            .as_deref()
+            .or(name)
+            .unwrap_or_else(|| rand_name.as_ref());
+        let mut state = self.new_job(&cmd, name, url, Vec::new(), checksum).await?;
+        state.variant = variant;
+        self.state_client.update(state.clone()).await?;
+        self.emit(EventKind::Added, &state);
+        self.enqueue(&state);
+        Ok(Message::Ack(AckCommand {}))
+    }
+    // a failed job doesn't stop the rest, each one gets its own result
+    async fn add_batch(&mut self, cmd: AddBatchCommand) -> Result<Message, ManagerError> {
+        let mut results = Vec::new();
+        for job in cmd.jobs {
+            let url = job.url.clone();
+            let error = self.add(job).await.err().map(|e| e.to_string());
+            results.push(AddResult { url, error });
+        }
+        Ok(Message::AddBatchResponse(AddBatchResponse { results }))
+    }
+    // each file of the document becomes a job downloaded from all of its mirrors
+    async fn add_metalink(&mut self, cmd: &AddCommand, doc: &str) -> Result<Message, ManagerError> {
+        let mut files = metalink::parse(doc)?;
+        let checksum = match &cmd.checksum {
+            Some(c) => Some(c.parse::<Checksum>()?),
+            None => None,
+        };
+        // the name only applies to a document of a single file
+        if let (Some(name), [file]) = (&cmd.name, files.as_mut_slice()) {
+            file.name = name.clone();
+        }
+        // everything is checked first so that a bad file doesn't leave the others half added
+        for file in files.iter_mut() {
+            file.urls.retain(|url| Protocol::of(url).is_ok());
+            if file.urls.is_empty() {
+                return Err(ManagerError {
+                    kind: UnsupportedProtocol,
+                    msg: format!("{} has no http or ftp urls", file.name),
+                });
+            }
+            if self.job_exists(&file.name).await? {
+                return Err(ManagerError {
+                    kind: DownloadJobNameAlreadyExist,
+                    msg: format!("{} already exists", file.name),
+                });
+            }
+        }
+        for file in files {
+            let mut state = self
+                .new_job(
+                    cmd,
+                    &file.name,
+                    &file.urls[0],
+                    file.urls[1..].to_vec(),
+                    checksum.clone().or(file.checksum),
+                )
+                .await?;
+            state.total = file.size.unwrap_or(0);
+            self.state_client.update(state.clone()).await?;
+            if let (Some(pieces), Some(size)) = (file.pieces, file.size) {
+                self.state_client
+                    .update_segments(&state.name, pieces.segments(size))
+                    .await?;
+            }
+            self.emit(EventKind::Added, &state);
+            self.enqueue(&state);
+        }
+        Ok(Message::Ack(AckCommand {}))
+    }
+    async fn new_job(
+        &mut self,
+        cmd: &AddCommand,
+        name: &str,
+        url: &str,
+        mirrors: Vec<String>,
+        checksum: Option<Checksum>,
+    ) -> Result<JobInfo, ManagerError> {
         if self.job_exists(name).await? {
             return Err(ManagerError {
                 kind: DownloadJobNameAlreadyExist,
                 msg: format!("{} already exists", name),
             });
         }
-        let (tx, rx) = watch::channel(CancelInfo {
-            cancel: false,
-            delete: false,
-        });
-        let file_path = Path::new(&self.downloads_path).join(name);
-        let job = DownloadJob {
+        cmd.request.header_map()?;
+        if let Some(text) = &cmd.cookies_txt {
+            cookies::parse(text)?;
+        }
+        if proxy::parse(&cmd.request.proxy)?.is_some_and(|p| p.password().is_some()) {
+            self.job_proxies
+                .insert(name.into(), cmd.request.proxy.clone());
+        }
+        let file_path = Path::new(&self.config.downloads).join(name);
+        let segments = cmd.segments.unwrap_or(self.config.segments).max(1);
+        let max_attempts = cmd.max_attempts.unwrap_or(self.config.max_attempts).max(1);
+        let rate_limit = cmd.rate_limit.unwrap_or(0);
+        let priority = cmd.priority.unwrap_or(0);
+        let position = self.next_position;
+        self.next_position += 1;
+        Ok(JobInfo {
             name: name.into(),
-            // TODO: make configurable
-            file_path: file_path.clone(),
-            url: url.to_string(),
-            cancel_channel: rx,
-        };
-        self.state_client
-            .update(JobInfo {
-                name: name.into(),
-                url: url.into(),
-                path: file_path
-                    .to_str()
-                    .unwrap_or("invalid path, shouldn't happen")
-                    .into(),
-                downloaded: 0,
-                total: 0,
-                state: State::Pending,
-                msg: "".into(),
-            })
-            .await?;
-        self.job_sender.send(job).await?;
-        self.cancel_channels.insert(name.into(), tx);
-        Ok(Message::Ack(AckCommand {}))
+            url: url.into(),
+            path: file_path
+                .to_str()
+                .unwrap_or("invalid path, shouldn't happen")
+                .into(),
+            downloaded: 0,
+            total: 0,
+            state: State::Pending,
+            msg: "".into(),
+            segments,
+            checksum: checksum.map(|c| c.to_string()).unwrap_or_default(),
+            attempts: 0,
+            max_attempts,
+            next_retry: 0,
+            rate_limit,
+            speed: 0,
+            started_at: 0,
+            finished_at: 0,
+            priority,
+            position,
+            start_at: cmd.start_at.unwrap_or(0),
+            etag: "".into(),
+            last_modified: "".into(),
+            mirrors,
+            variant: "".into(),
+            request: cmd.request.clone(),
+            used_proxy: "".into(),
+            cookies_txt: cmd.cookies_txt.clone().unwrap_or_default(),
+        })
     }
     async fn list(&self) -> Result<Message, ManagerError> {
         Ok(Message::ListResponse(ListResponse::from(
@@ -229,6 +460,8 @@ impl ManagerDaemon {
         forget: bool,
         delete: bool,
     ) -> Result<Message, ManagerError> {
+        self.job_limiters.remove(name);
+        self.job_orders.remove(name);
         if let Some(ch) = self.cancel_channels.remove(name) {
             // deleting a non-existent job will succceed (bad?)
             ch.send(CancelInfo {
@@ -238,16 +471,30 @@ impl ManagerDaemon {
             self.state_client
                 .update_job_state(name, State::Cancelled)
                 .await?;
+            if let Ok(state) = self.state_client.get(name).await {
+                self.emit(EventKind::Cancelled, &state);
+            }
         }
         if delete {
             let state = self.state_client.get(name).await?;
-            if let Err(e) = std::fs::remove_file(state.path) {
+            let path = Path::new(&state.path);
+            let part = part_path(path, &self.config.incomplete);
+            for path in [path.to_path_buf(), part.clone()] {
+                if let Err(e) = std::fs::remove_file(path) {
+                    if e.kind() != ErrorKind::NotFound {
+                        return Err(e.into());
+                    }
+                }
+            }
+            if let Err(e) = std::fs::remove_dir_all(hls::segments_dir(&part)) {
                 if e.kind() != ErrorKind::NotFound {
                     return Err(e.into());
                 }
             }
         }
         if delete || forget {
+            self.forget_running(name);
+            self.job_proxies.remove(name);
             if let Err(e) = self.state_client.delete(name).await {
                 if e.kind != DownloadJobNotFound {
                     // abort on database errors only
@@ -259,4 +506,329 @@ impl ManagerDaemon {
         }
         Ok(Message::Ack(AckCommand))
     }
+    async fn pause(&mut self, name: &str) -> Result<Message, ManagerError> {
+        let state = self.state_client.get(name).await?;
+        if state.state != State::Pending && state.state != State::Active {
+            return Err(ManagerError {
+                kind: InvalidJobState,
+                msg: format!(
+                    "{} is {}, only pending or active jobs can be paused",
+                    name, state.state
+                ),
+            });
+        }
+        let ch = match self.cancel_channels.remove(name) {
+            Some(ch) => ch,
+            None => {
+                return Err(ManagerError {
+                    kind: InvalidJobState,
+                    msg: format!("{} isn't queued or running", name),
+                });
+            }
+        };
+        self.job_limiters.remove(name);
+        self.job_orders.remove(name);
+        // the worker stops the same way as in a cancel, the partial file is kept
+        ch.send(CancelInfo {
+            cancel: true,
+            delete: false,
+        })?;
+        self.state_client
+            .update_job_state(name, State::Paused)
+            .await?;
+        self.emit(
+            EventKind::Paused,
+            &JobInfo {
+                state: State::Paused,
+                ..state
+            },
+        );
+        Ok(Message::Ack(AckCommand))
+    }
+    async fn resume(&mut self, name: &str) -> Result<Message, ManagerError> {
+        let state = self.state_client.get(name).await?;
+        if state.state != State::Paused {
+            return Err(ManagerError {
+                kind: InvalidJobState,
+                msg: format!(
+                    "{} is {}, only paused jobs can be resumed",
+                    name, state.state
+                ),
+            });
+        }
+        self.state_client
+            .update_job_state(name, State::Pending)
+            .await?;
+        // a manual resume starts counting attempts again
+        self.enqueue(&JobInfo {
+            attempts: 0,
+            next_retry: 0,
+            ..state.clone()
+        });
+        self.emit(
+            EventKind::Resumed,
+            &JobInfo {
+                state: State::Pending,
+                ..state
+            },
+        );
+        Ok(Message::Ack(AckCommand))
+    }
+    // applies the settings that can change without a restart
+    async fn reload(&mut self, config: Config) {
+        println!("reloading the config");
+        if config.addr != self.config.addr
+            || config.workers != self.config.workers
+            || config.database != self.config.database
+            || config.incomplete != self.config.incomplete
+            || config.cookie_jar != self.config.cookie_jar
+        {
+            println!(
+                "the address, workers, database, incomplete directory and cookie jar only change after a restart"
+            );
+        }
+        self.global_limiter.set_rate(config.limit);
+        if !config.cookies.is_empty() {
+            if let Err(e) = import_cookies(&self.cookies, &config.cookies) {
+                println!("failed to import the cookies {}", e);
+            }
+        }
+        if let Err(e) = load_credentials(&self.credentials, &config) {
+            println!("failed to load the credentials {}", e);
+        }
+        self.config = Config {
+            addr: self.config.addr,
+            workers: self.config.workers,
+            database: self.config.database.clone(),
+            incomplete: self.config.incomplete.clone(),
+            cookie_jar: self.config.cookie_jar.clone(),
+            ..config
+        };
+        self.apply_schedule().await;
+    }
+    // hands the job to the workers, holding it back until its start time or scheduled retry
+    fn enqueue(&mut self, state: &JobInfo) {
+        let (tx, rx) = watch::channel(CancelInfo {
+            cancel: false,
+            delete: false,
+        });
+        let job = DownloadJob {
+            name: state.name.clone(),
+            file_path: Path::new(&state.path).to_path_buf(),
+            part_path: part_path(Path::new(&state.path), &self.config.incomplete),
+            url: state.url.clone(),
+            mirrors: state.mirrors.clone(),
+            // checked when the job was added
+            protocol: Protocol::of(&state.url).unwrap_or(Protocol::Http),
+            hls: match state.variant.as_str() {
+                "" => None,
+                v => v.parse().ok(),
+            },
+            segments: state.segments,
+            checksum: state.checksum.parse().ok(),
+            attempts: state.attempts,
+            max_attempts: state.max_attempts,
+            limiter: self.job_limiter(&state.name, state.rate_limit),
+            started_at: state.started_at,
+            order: self.job_order(&state.name, state.priority, state.position),
+            validators: Validators::from(state),
+            request: RequestOptions {
+                proxy: self
+                    .job_proxies
+                    .get(&state.name)
+                    .cloned()
+                    .unwrap_or_else(|| state.request.proxy.clone()),
+                ..state.request.clone()
+            },
+            cookies: self.cookies.clone(),
+            cookies_txt: state.cookies_txt.clone(),
+            proxies: self.config.proxies.clone(),
+            credentials: self.credentials.clone(),
+            cancel_channel: rx,
+            running: self
+                .job_running
+                .entry(state.name.clone())
+                .or_default()
+                .clone(),
+            run: 0,
+        };
+        self.cancel_channels.insert(state.name.clone(), tx);
+        let not_before = state.start_at.max(state.next_retry);
+        // also when it's due, a paused or held job can still be stopping
+        requeue_after(
+            self.queue.clone(),
+            job,
+            time::Duration::from_secs(not_before.saturating_sub(unix_now())),
+        );
+    }
+    // opens or closes the queue as the download windows start and end
+    async fn apply_schedule(&mut self) {
+        let open = self.config.schedule.is_open(Local::now());
+        if open == self.queue.is_open() {
+            return;
+        }
+        println!("download window {}", if open { "opened" } else { "closed" });
+        self.queue.set_open(open);
+        if !open {
+            if let Err(e) = self.hold_active_jobs().await {
+                println!("failed to stop the active jobs {}", e)
+            }
+        }
+    }
+    // stops the running downloads and queues them again to continue in the next window
+    async fn hold_active_jobs(&mut self) -> Result<(), ManagerError> {
+        for state in self.state_client.list().await? {
+            if state.state != State::Active {
+                continue;
+            }
+            if let Some(ch) = self.cancel_channels.remove(&state.name) {
+                ch.send(CancelInfo {
+                    cancel: true,
+                    delete: false,
+                })?;
+            }
+            self.state_client
+                .update_job_state(&state.name, State::Pending)
+                .await?;
+            let state = JobInfo {
+                state: State::Pending,
+                speed: 0,
+                ..state
+            };
+            self.emit(EventKind::Paused, &state);
+            self.enqueue(&state);
+        }
+        Ok(())
+    }
+    // the lock is kept while a worker still holds it so that a job added again under the same
+    // name waits for it too
+    fn forget_running(&mut self, name: &str) {
+        if let Some(running) = self.job_running.get(name) {
+            if running.try_lock().is_ok() {
+                self.job_running.remove(name);
+            }
+        }
+    }
+    fn job_limiter(&mut self, name: &str, rate_limit: u64) -> RateLimiter {
+        let limiter = RateLimiter::new(rate_limit);
+        self.job_limiters.insert(name.into(), limiter.clone());
+        limiter
+    }
+    fn job_order(&mut self, name: &str, priority: i32, position: i64) -> QueueOrder {
+        let order = QueueOrder::new(priority, position);
+        self.job_orders.insert(name.into(), order.clone());
+        order
+    }
+    async fn reorder(&mut self, name: &str, reorder: Reorder) -> Result<Message, ManagerError> {
+        let state = self.state_client.get(name).await?;
+        if state.state != State::Pending && state.state != State::Paused {
+            return Err(ManagerError {
+                kind: InvalidJobState,
+                msg: format!(
+                    "{} is {}, only pending or paused jobs can be reordered",
+                    name, state.state
+                ),
+            });
+        }
+        let (priority, position) = match reorder {
+            Reorder::Priority(priority) => (priority, state.position),
+            // ahead of every waiting job: the highest priority and the lowest position
+            Reorder::Front => {
+                let waiting = self.waiting_jobs(name).await?;
+                (
+                    waiting
+                        .iter()
+                        .map(|s| s.priority)
+                        .fold(state.priority, i32::max),
+                    waiting
+                        .iter()
+                        .map(|s| s.position)
+                        .fold(state.position, i64::min)
+                        - 1,
+                )
+            }
+            Reorder::Back => {
+                let waiting = self.waiting_jobs(name).await?;
+                self.next_position += 1;
+                (
+                    waiting
+                        .iter()
+                        .map(|s| s.priority)
+                        .fold(state.priority, i32::min),
+                    self.next_position - 1,
+                )
+            }
+        };
+        self.state_client
+            .update_order(name, priority, position)
+            .await?;
+        if let Some(order) = self.job_orders.get(name) {
+            order.set(priority, position);
+        }
+        Ok(Message::Ack(AckCommand))
+    }
+    // the pending jobs other than the given one
+    async fn waiting_jobs(&self, name: &str) -> Result<Vec<JobInfo>, ManagerError> {
+        Ok(self
+            .state_client
+            .list()
+            .await?
+            .into_iter()
+            .filter(|s| s.state == State::Pending && s.name != name)
+            .collect())
+    }
+    async fn limit(
+        &mut self,
+        name: Option<&str>,
+        rate_limit: u64,
+    ) -> Result<Message, ManagerError> {
+        match name {
+            None => self.global_limiter.set_rate(rate_limit),
+            Some(name) => {
+                // fails if the job doesn't exist
+                self.state_client.get(name).await?;
+                self.state_client
+                    .update_rate_limit(name, rate_limit)
+                    .await?;
+                if let Some(limiter) = self.job_limiters.get(name) {
+                    limiter.set_rate(rate_limit);
+                }
+            }
+        }
+        Ok(Message::Ack(AckCommand))
+    }
+    // kept out of the database, jobs added later and the running ones use them right away
+    fn add_credentials(&self, cmd: CredentialsCommand) -> Result<Message, ManagerError> {
+        self.credentials.add(
+            &cmd.host,
+            Credential {
+                user: cmd.user,
+                password: cmd.password,
+            },
+        )?;
+        Ok(Message::Ack(AckCommand))
+    }
+    fn emit(&self, kind: EventKind, state: &JobInfo) {
+        // fails only if nobody is listening
+        let _ = self.events.send(Event::new(kind, state));
+    }
+}
+
+// the netrc entries, then the ones of the config going before them
+fn load_credentials(store: &Credentials, config: &Config) -> Result<(), ManagerError> {
+    let mut entries = credentials::read_netrc(&config.netrc)?;
+    entries.extend(config.credentials.iter().cloned());
+    println!("loaded the credentials of {} hosts", entries.len());
+    store.configure(entries);
+    Ok(())
+}
+
+fn import_cookies(jar: &CookieJar, path: &str) -> Result<(), ManagerError> {
+    let text = std::fs::read_to_string(path).map_err(|e| ManagerError {
+        kind: IO,
+        msg: format!("couldn't read {}: {}", path, e),
+    })?;
+    let count = jar.import(&text)?;
+    println!("imported {} cookies from {}", count, path);
+    Ok(())
 }
@@ -0,0 +1,409 @@
+use super::protocol::http::Client;
+use super::retry::DownloadError;
+use crate::err::{ManagerError, ManagerErrorKind};
+use aes::Aes128;
+use cbc::cipher::block_padding::Pkcs7;
+use cbc::cipher::{BlockDecryptMut, KeyIvInit};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use url::Url;
+
+// which rendition of a master playlist is downloaded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Variant {
+    Best,
+    Worst,
+    // the best one at most this many lines high
+    Height(u32),
+}
+
+impl FromStr for Variant {
+    type Err = ManagerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "" | "best" => Ok(Variant::Best),
+            "worst" => Ok(Variant::Worst),
+            h => h
+                .trim_end_matches('p')
+                .parse()
+                .map(Variant::Height)
+                .map_err(|_| ManagerError {
+                    kind: ManagerErrorKind::InvalidVariant,
+                    msg: format!("{} should be best, worst or a height like 720p", s),
+                }),
+        }
+    }
+}
+
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Variant::Best => write!(f, "best"),
+            Variant::Worst => write!(f, "worst"),
+            Variant::Height(h) => write!(f, "{}p", h),
+        }
+    }
+}
+
+// an AES-128 key and the iv of a segment
+#[derive(Clone, Debug)]
+pub struct Key {
+    pub url: Url,
+    pub iv: [u8; 16],
+}
+
+#[derive(Clone, Debug)]
+pub struct MediaSegment {
+    pub url: Url,
+    // inclusive byte range of a resource shared by several segments
+    pub range: Option<(u64, u64)>,
+    pub key: Option<Key>,
+}
+
+// playlists are usually named like this, other urls need --hls
+pub fn is_playlist(url: &str) -> bool {
+    Url::parse(url)
+        .map(|u| u.path().to_lowercase().ends_with(".m3u8"))
+        .unwrap_or(false)
+}
+
+// the finished segments are kept here until they're joined into the part file
+pub fn segments_dir(part_path: &Path) -> PathBuf {
+    part_path.with_extension("segments")
+}
+
+// fetches the media playlist, going through the master playlist to the chosen variant
+pub async fn resolve(
+    client: &Client,
+    url: &str,
+    variant: Variant,
+) -> Result<Vec<MediaSegment>, DownloadError> {
+    let mut url = Url::parse(url).map_err(|e| format!("invalid url {}", e))?;
+    let mut playlist = fetch_playlist(client, &url).await?;
+    if playlist.contains("#EXT-X-STREAM-INF") {
+        url = choose(&url, &playlist, variant)?;
+        playlist = fetch_playlist(client, &url).await?;
+    }
+    parse_media(&url, &playlist)
+}
+
+pub async fn fetch_key(client: &Client, url: &Url) -> Result<[u8; 16], DownloadError> {
+    let res = client
+        .send(client.get(url.clone()))
+        .await
+        .map_err(|e| DownloadError::from_reqwest("couldn't fetch the key: ", e))?;
+    if let Some(e) = DownloadError::from_response(&res) {
+        return Err(e);
+    }
+    let key = res
+        .bytes()
+        .await
+        .map_err(|e| DownloadError::from_reqwest("couldn't fetch the key: ", e))?;
+    key.as_ref()
+        .try_into()
+        .map_err(|_| format!("the key at {} isn't 16 bytes long", url).into())
+}
+
+pub fn decrypt(key: &[u8; 16], iv: &[u8; 16], data: &mut Vec<u8>) -> Result<(), DownloadError> {
+    let len = cbc::Decryptor::<Aes128>::new(key.into(), iv.into())
+        .decrypt_padded_mut::<Pkcs7>(data)
+        .map_err(|_| "failed to decrypt a segment, wrong key or iv")?
+        .len();
+    data.truncate(len);
+    Ok(())
+}
+
+async fn fetch_playlist(client: &Client, url: &Url) -> Result<String, DownloadError> {
+    let res = client
+        .send(client.get(url.clone()))
+        .await
+        .map_err(|e| DownloadError::from_reqwest("couldn't fetch the playlist: ", e))?;
+    if let Some(e) = DownloadError::from_response(&res) {
+        return Err(e);
+    }
+    let playlist = res
+        .text()
+        .await
+        .map_err(|e| DownloadError::from_reqwest("couldn't fetch the playlist: ", e))?;
+    if !playlist.trim_start().starts_with("#EXTM3U") {
+        return Err(format!("{} isn't an hls playlist", url).into());
+    }
+    Ok(playlist)
+}
+
+fn choose(base: &Url, playlist: &str, variant: Variant) -> Result<Url, DownloadError> {
+    // bandwidth, height and uri of each variant
+    let mut variants = Vec::new();
+    let mut lines = playlist.lines().map(str::trim);
+    while let Some(line) = lines.next() {
+        let attrs = match line.strip_prefix("#EXT-X-STREAM-INF:") {
+            Some(v) => attributes(v),
+            None => continue,
+        };
+        let uri = match lines.find(|l| !l.is_empty() && !l.starts_with('#')) {
+            Some(v) => v,
+            None => break,
+        };
+        let bandwidth: u64 = attrs
+            .get("BANDWIDTH")
+            .and_then(|b| b.parse().ok())
+            .unwrap_or(0);
+        let height: Option<u32> = attrs
+            .get("RESOLUTION")
+            .and_then(|r| r.split_once('x'))
+            .and_then(|(_, h)| h.parse().ok());
+        variants.push((bandwidth, height, uri));
+    }
+    let worst = variants.iter().min_by_key(|(b, _, _)| *b);
+    let chosen = match variant {
+        Variant::Best => variants.iter().max_by_key(|(b, _, _)| *b),
+        Variant::Worst => worst,
+        // falls back to the smallest one if they're all higher
+        Variant::Height(max) => variants
+            .iter()
+            .filter(|(_, h, _)| h.is_some_and(|h| h <= max))
+            .max_by_key(|(b, _, _)| *b)
+            .or(worst),
+    };
+    let (_, _, uri) = chosen.ok_or("the master playlist has no variants")?;
+    base.join(uri)
+        .map_err(|e| format!("invalid variant url {}", e).into())
+}
+
+fn parse_media(base: &Url, playlist: &str) -> Result<Vec<MediaSegment>, DownloadError> {
+    let mut segments = Vec::new();
+    let mut sequence: u128 = 0;
+    // the key url and the explicit iv, if any
+    let mut key: Option<(Url, Option<[u8; 16]>)> = None;
+    let mut range = None;
+    // a byte range without an offset follows the previous one of the same resource
+    let mut next_offset = 0;
+    let mut map = None;
+    for line in playlist.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        if let Some(v) = line.strip_prefix("#EXT-X-MEDIA-SEQUENCE:") {
+            sequence = v.parse().map_err(|_| "invalid media sequence")?;
+        } else if let Some(v) = line.strip_prefix("#EXT-X-KEY:") {
+            let attrs = attributes(v);
+            key = match attrs.get("METHOD").map(String::as_str) {
+                Some("NONE") => None,
+                Some("AES-128") => {
+                    let uri = attrs.get("URI").ok_or("an AES-128 key has no uri")?;
+                    let url = base
+                        .join(uri)
+                        .map_err(|e| format!("invalid key url {}", e))?;
+                    let iv = match attrs.get("IV") {
+                        Some(iv) => Some(parse_iv(iv)?),
+                        None => None,
+                    };
+                    Some((url, iv))
+                }
+                method => {
+                    return Err(
+                        format!("unsupported encryption {}", method.unwrap_or_default()).into(),
+                    )
+                }
+            };
+        } else if let Some(v) = line.strip_prefix("#EXT-X-BYTERANGE:") {
+            range = Some(parse_range(v, next_offset)?);
+        } else if let Some(v) = line.strip_prefix("#EXT-X-MAP:") {
+            // the init section of fragmented mp4 streams goes first, once
+            let attrs = attributes(v);
+            let uri = attrs
+                .get("URI")
+                .ok_or("a media initialization section has no uri")?;
+            let url = base
+                .join(uri)
+                .map_err(|e| format!("invalid segment url {}", e))?;
+            let range = match attrs.get("BYTERANGE") {
+                Some(r) => Some(parse_range(r, 0)?),
+                None => None,
+            };
+            if map.as_ref() != Some(&(url.clone(), range)) {
+                segments.push(MediaSegment {
+                    url: url.clone(),
+                    range,
+                    key: None,
+                });
+                map = Some((url, range));
+            }
+        } else if !line.starts_with('#') {
+            let url = base
+                .join(line)
+                .map_err(|e| format!("invalid segment url {}", e))?;
+            if let Some((_, end)) = range {
+                next_offset = end + 1;
+            }
+            segments.push(MediaSegment {
+                url,
+                range: range.take(),
+                // without an iv the media sequence number is used
+                key: key.as_ref().map(|(url, iv)| Key {
+                    url: url.clone(),
+                    iv: iv.unwrap_or_else(|| sequence.to_be_bytes()),
+                }),
+            });
+            sequence += 1;
+        }
+    }
+    Ok(segments)
+}
+
+// the attribute lists of tags, values can be quoted and contain commas
+fn attributes(list: &str) -> HashMap<String, String> {
+    let mut attrs = HashMap::new();
+    let mut rest = list;
+    while let Some((name, value)) = rest.split_once('=') {
+        let (value, next) = match value.strip_prefix('"') {
+            Some(quoted) => {
+                let (value, next) = quoted.split_once('"').unwrap_or((quoted, ""));
+                (value, next.trim_start_matches(','))
+            }
+            None => value.split_once(',').unwrap_or((value, "")),
+        };
+        attrs.insert(name.trim().to_string(), value.to_string());
+        rest = next;
+    }
+    attrs
+}
+
+// <length>[@<offset>]
+fn parse_range(v: &str, next_offset: u64) -> Result<(u64, u64), DownloadError> {
+    let invalid = || DownloadError::from(format!("invalid byte range {}", v));
+    let (len, offset) = match v.split_once('@') {
+        Some((len, offset)) => (len, offset.parse().map_err(|_| invalid())?),
+        None => (v, next_offset),
+    };
+    let len: u64 = len.parse().map_err(|_| invalid())?;
+    // where the next range continues has to fit too
+    match offset.checked_add(len) {
+        Some(next) if len > 0 => Ok((offset, next - 1)),
+        _ => Err(invalid()),
+    }
+}
+
+fn parse_iv(v: &str) -> Result<[u8; 16], DownloadError> {
+    let hex = v.trim_start_matches("0x").trim_start_matches("0X");
+    u128::from_str_radix(hex, 16)
+        .map(|iv| iv.to_be_bytes())
+        .map_err(|_| format!("invalid iv {}", v).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASTER: &str = "#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=800000,RESOLUTION=640x360,CODECS=\"avc1.4d401e,mp4a.40.2\"
+360p/index.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=5000000,RESOLUTION=1920x1080
+1080p/index.m3u8
+
+#EXT-X-STREAM-INF:BANDWIDTH=2500000,RESOLUTION=1280x720
+https://cdn.example.com/720p/index.m3u8
+";
+
+    fn base() -> Url {
+        Url::parse("https://example.com/live/master.m3u8").unwrap()
+    }
+
+    fn chosen(variant: &str) -> String {
+        choose(&base(), MASTER, variant.parse().unwrap())
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn chooses_a_variant() {
+        assert_eq!(chosen("best"), "https://example.com/live/1080p/index.m3u8");
+        assert_eq!(chosen("worst"), "https://example.com/live/360p/index.m3u8");
+        assert_eq!(chosen("720p"), "https://cdn.example.com/720p/index.m3u8");
+        assert_eq!(chosen("1000"), "https://cdn.example.com/720p/index.m3u8");
+        // all of them are higher
+        assert_eq!(chosen("240p"), "https://example.com/live/360p/index.m3u8");
+        assert!(choose(&base(), "#EXTM3U\n", Variant::Best).is_err());
+        assert!("hd".parse::<Variant>().is_err());
+    }
+
+    #[test]
+    fn parses_a_media_playlist() {
+        let segments = parse_media(
+            &base(),
+            "#EXTM3U
+#EXT-X-TARGETDURATION:10
+#EXT-X-MEDIA-SEQUENCE:7
+#EXT-X-MAP:URI=\"init.mp4\"
+#EXTINF:10,
+a.ts
+#EXT-X-KEY:METHOD=AES-128,URI=\"https://keys.example.com/k1\"
+#EXTINF:10,
+b.ts
+#EXT-X-KEY:METHOD=AES-128,URI=\"k2\",IV=0x000102030405060708090a0b0c0d0e0f
+#EXT-X-BYTERANGE:1000@0
+#EXTINF:10,
+c.ts
+#EXT-X-BYTERANGE:500
+#EXTINF:10,
+c.ts
+#EXT-X-KEY:METHOD=NONE
+#EXTINF:10,
+d.ts
+#EXT-X-ENDLIST
+",
+        )
+        .unwrap();
+        let urls: Vec<&str> = segments.iter().map(|s| s.url.path()).collect();
+        assert_eq!(
+            urls,
+            [
+                "/live/init.mp4",
+                "/live/a.ts",
+                "/live/b.ts",
+                "/live/c.ts",
+                "/live/c.ts",
+                "/live/d.ts"
+            ]
+        );
+        assert!(segments[0].key.is_none() && segments[1].key.is_none());
+        // without an iv the media sequence number is used, b.ts is the second one
+        let key = segments[2].key.as_ref().unwrap();
+        assert_eq!(key.url.as_str(), "https://keys.example.com/k1");
+        assert_eq!(key.iv, 8u128.to_be_bytes());
+        let key = segments[3].key.as_ref().unwrap();
+        assert_eq!(key.url.as_str(), "https://example.com/live/k2");
+        assert_eq!(
+            key.iv,
+            [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]
+        );
+        assert_eq!(segments[3].range, Some((0, 999)));
+        assert_eq!(segments[4].range, Some((1000, 1499)));
+        assert!(segments[5].key.is_none() && segments[5].range.is_none());
+    }
+
+    #[test]
+    fn rejects_unsupported_encryption() {
+        let playlist = "#EXTM3U\n#EXT-X-KEY:METHOD=SAMPLE-AES,URI=\"k\"\na.ts\n";
+        assert!(parse_media(&base(), playlist).is_err());
+        assert!(parse_media(&base(), "#EXTM3U\n#EXT-X-BYTERANGE:0@10\na.ts\n").is_err());
+    }
+
+    #[test]
+    fn rejects_ranges_past_the_end() {
+        assert_eq!(
+            parse_range("1@18446744073709551614", 0).unwrap(),
+            (u64::MAX - 1, u64::MAX - 1)
+        );
+        assert!(parse_range("1@18446744073709551615", 0).is_err());
+        assert!(parse_range("18446744073709551615@1", 0).is_err());
+        assert!(parse_range("10", u64::MAX).is_err());
+        let playlist = "#EXTM3U
+#EXT-X-BYTERANGE:1@18446744073709551614
+a.ts
+#EXT-X-BYTERANGE:1
+a.ts
+";
+        assert!(parse_media(&base(), playlist).is_err());
+    }
+}
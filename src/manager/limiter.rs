@@ -0,0 +1,144 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time;
+
+// the longest a transfer sleeps before checking the rate again
+const MAX_SLEEP: Duration = Duration::from_millis(100);
+
+// a token bucket shared by everything holding a clone of it, a rate of 0 means unlimited
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    // bytes per second, also the burst size
+    rate: u64,
+    // goes negative when a chunk bigger than what's available is taken
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let rate = self.rate as f64;
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * rate).min(rate);
+        self.last = now;
+    }
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        RateLimiter {
+            bucket: Arc::new(Mutex::new(Bucket {
+                rate,
+                tokens: 0.0,
+                last: Instant::now(),
+            })),
+        }
+    }
+    pub fn rate(&self) -> u64 {
+        self.bucket.lock().unwrap().rate
+    }
+    pub fn set_rate(&self, rate: u64) {
+        let mut b = self.bucket.lock().unwrap();
+        b.refill();
+        b.rate = rate;
+        // what was taken beyond the old rate isn't owed anymore once unlimited
+        b.tokens = if rate == 0 {
+            0.0
+        } else {
+            b.tokens.min(rate as f64)
+        };
+    }
+    // waits until n bytes are allowed through. the wait is taken in slices and recomputed each
+    // time, so that a rate changed meanwhile applies to the transfers already waiting
+    pub async fn acquire(&self, n: u64) {
+        {
+            let mut b = self.bucket.lock().unwrap();
+            if b.rate == 0 {
+                return;
+            }
+            b.refill();
+            b.tokens -= n as f64;
+        }
+        loop {
+            let wait = {
+                let mut b = self.bucket.lock().unwrap();
+                if b.rate == 0 {
+                    return;
+                }
+                b.refill();
+                if b.tokens >= 0.0 {
+                    return;
+                }
+                Duration::from_secs_f64(-b.tokens / b.rate as f64)
+            };
+            time::sleep(wait.min(MAX_SLEEP)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // how long acquiring n bytes takes
+    async fn timed(limiter: &RateLimiter, n: u64) -> Duration {
+        let start = Instant::now();
+        limiter.acquire(n).await;
+        start.elapsed()
+    }
+
+    #[tokio::test]
+    async fn unlimited_never_waits() {
+        let limiter = RateLimiter::new(0);
+        assert!(timed(&limiter, 1 << 40).await < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn waits_for_the_rate() {
+        let limiter = RateLimiter::new(10_000);
+        let took = timed(&limiter, 2_000).await;
+        assert!(took >= Duration::from_millis(180), "{:?}", took);
+        assert!(took < Duration::from_secs(1), "{:?}", took);
+        // clones share the bucket
+        let other = limiter.clone();
+        let start = Instant::now();
+        tokio::join!(limiter.acquire(1_000), other.acquire(1_000));
+        assert!(start.elapsed() >= Duration::from_millis(180));
+    }
+
+    #[tokio::test]
+    async fn sets_the_rate() {
+        let limiter = RateLimiter::new(1_000);
+        assert_eq!(limiter.rate(), 1_000);
+        limiter.set_rate(0);
+        assert_eq!(limiter.rate(), 0);
+        assert!(timed(&limiter, 1 << 40).await < Duration::from_millis(50));
+        limiter.set_rate(10_000);
+        let took = timed(&limiter, 2_000).await;
+        assert!(took >= Duration::from_millis(180), "{:?}", took);
+    }
+
+    #[tokio::test]
+    async fn applies_a_new_rate_to_waiting_transfers() {
+        let limiter = RateLimiter::new(100);
+        let other = limiter.clone();
+        tokio::spawn(async move {
+            time::sleep(Duration::from_millis(50)).await;
+            other.set_rate(0);
+        });
+        // would take 10s at the old rate
+        assert!(timed(&limiter, 1_000).await < Duration::from_secs(1));
+        limiter.set_rate(100);
+        let other = limiter.clone();
+        tokio::spawn(async move {
+            time::sleep(Duration::from_millis(50)).await;
+            other.set_rate(1_000_000);
+        });
+        assert!(timed(&limiter, 1_000).await < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,269 @@
+use super::checksum::{Algorithm, Checksum};
+use crate::err::{ManagerError, ManagerErrorKind};
+use crate::types::Segment;
+use roxmltree::{Document, Node};
+
+// a file of a metalink document, both RFC 5854 (.meta4) and the older 3.0 format (.metalink)
+// are read, the elements are matched by name regardless of the namespace
+#[derive(Debug)]
+pub struct MetalinkFile {
+    pub name: String,
+    pub size: Option<u64>,
+    // the most preferred first
+    pub urls: Vec<String>,
+    pub checksum: Option<Checksum>,
+    pub pieces: Option<Pieces>,
+}
+
+// hashes of consecutive pieces of the file, the last one can be shorter
+#[derive(Debug)]
+pub struct Pieces {
+    pub length: u64,
+    pub hashes: Vec<Checksum>,
+}
+
+impl Pieces {
+    // the pieces are downloaded as segments of their own
+    pub fn segments(&self, size: u64) -> Vec<Segment> {
+        self.hashes
+            .iter()
+            .enumerate()
+            .map(|(i, hash)| {
+                let start = i as u64 * self.length;
+                Segment {
+                    idx: i as u32,
+                    start,
+                    end: (start + self.length).min(size) - 1,
+                    downloaded: 0,
+                    hash: hash.to_string(),
+                }
+            })
+            .collect()
+    }
+}
+
+pub fn parse(doc: &str) -> Result<Vec<MetalinkFile>, ManagerError> {
+    let doc = Document::parse(doc).map_err(|e| invalid(e.to_string()))?;
+    let files = doc
+        .descendants()
+        .filter(|n| n.has_tag_name("file"))
+        .map(parse_file)
+        .collect::<Result<Vec<_>, _>>()?;
+    if files.is_empty() {
+        return Err(invalid("the document doesn't describe any file".into()));
+    }
+    Ok(files)
+}
+
+fn parse_file(file: Node) -> Result<MetalinkFile, ManagerError> {
+    let name = file.attribute("name").unwrap_or_default();
+    // files can be placed in subdirectories, the downloads are kept flat
+    let name = name.rsplit('/').next().unwrap_or_default();
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(invalid(format!("invalid file name {:?}", name)));
+    }
+    let size = match child(file, "size") {
+        Some(s) => Some(
+            s.text()
+                .unwrap_or_default()
+                .trim()
+                .parse()
+                .map_err(|_| invalid(format!("invalid size of {}", name)))?,
+        ),
+        None => None,
+    };
+    let mut urls = file
+        .descendants()
+        .filter(|n| n.has_tag_name("url"))
+        .filter_map(|n| {
+            let url = n.text()?.trim().to_string();
+            // 5854 priorities go up from 1, 3.0 preferences go down from 100
+            let rank = match (n.attribute("priority"), n.attribute("preference")) {
+                (Some(p), _) => p.parse().unwrap_or(u32::MAX),
+                (None, Some(p)) => 100u32.saturating_sub(p.parse().unwrap_or(0)),
+                (None, None) => u32::MAX,
+            };
+            Some((rank, url))
+        })
+        .collect::<Vec<_>>();
+    urls.sort_by_key(|(rank, _)| *rank);
+    if urls.is_empty() {
+        return Err(invalid(format!("{} has no urls", name)));
+    }
+    let hashes = file.descendants().filter(|n| {
+        n.has_tag_name("hash") && !n.parent().is_some_and(|p| p.has_tag_name("pieces"))
+    });
+    let checksum = strongest(hashes.filter_map(|n| checksum(n.attribute("type")?, n.text()?)));
+    let pieces = match size {
+        Some(size) => strongest(
+            file.descendants()
+                .filter(|n| n.has_tag_name("pieces"))
+                .filter_map(|n| parse_pieces(n, size)),
+        ),
+        None => None,
+    };
+    Ok(MetalinkFile {
+        name: name.into(),
+        size,
+        urls: urls.into_iter().map(|(_, url)| url).collect(),
+        checksum,
+        pieces,
+    })
+}
+
+// skips piece lists with an unsupported algorithm or that don't cover the file
+fn parse_pieces(pieces: Node, size: u64) -> Option<(Algorithm, Pieces)> {
+    let length: u64 = pieces.attribute("length")?.parse().ok()?;
+    let kind = pieces.attribute("type")?;
+    let hashes = pieces
+        .children()
+        .filter(|n| n.has_tag_name("hash"))
+        .map(|n| checksum(kind, n.text().unwrap_or_default()).map(|(_, c)| c))
+        .collect::<Option<Vec<_>>>()?;
+    if length == 0 || hashes.is_empty() || hashes.len() as u64 != size.div_ceil(length) {
+        return None;
+    }
+    Some((hashes[0].algorithm, Pieces { length, hashes }))
+}
+
+// sha-256 in 5854, sha256 in 3.0
+fn checksum(kind: &str, digest: &str) -> Option<(Algorithm, Checksum)> {
+    let c: Checksum = format!("{}:{}", kind.replace('-', ""), digest.trim())
+        .parse()
+        .ok()?;
+    Some((c.algorithm, c))
+}
+
+fn strongest<T>(items: impl Iterator<Item = (Algorithm, T)>) -> Option<T> {
+    items
+        .max_by_key(|(algorithm, _)| match algorithm {
+            Algorithm::Sha256 => 3,
+            Algorithm::Sha1 => 2,
+            Algorithm::Md5 => 1,
+        })
+        .map(|(_, item)| item)
+}
+
+fn child<'a, 'b>(node: Node<'a, 'b>, name: &str) -> Option<Node<'a, 'b>> {
+    node.children().find(|n| n.has_tag_name(name))
+}
+
+fn invalid(msg: String) -> ManagerError {
+    ManagerError {
+        kind: ManagerErrorKind::InvalidMetalink,
+        msg,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256: &str = "d6d582e8248e306270bbb5492369b4f7fe865cfc9593c20d8dfde0b7e00bf5d2";
+    const SHA1: &str = "095b66420d9fe26b9c01ce9b73872f499f671327";
+    const MD5: &str = "9e107d9d372bb6826bd81d3542a419d6";
+
+    #[test]
+    fn parses_a_meta4() {
+        let files = parse(&format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<metalink xmlns="urn:ietf:params:xml:ns:metalink">
+ <file name="dir/example.iso">
+  <size>2500</size>
+  <hash type="md5">{md5}</hash>
+  <hash type="sha-256">{sha256}</hash>
+  <pieces length="1000" type="sha-1">
+   <hash>{sha1}</hash><hash>{sha1}</hash><hash>{sha1}</hash>
+  </pieces>
+  <url priority="2">http://mirror.example.com/example.iso</url>
+  <url priority="1">https://example.com/example.iso</url>
+  <url>ftp://ftp.example.com/example.iso</url>
+ </file>
+</metalink>"#,
+            md5 = MD5,
+            sha256 = SHA256,
+            sha1 = SHA1
+        ))
+        .unwrap();
+        assert_eq!(files.len(), 1);
+        let file = &files[0];
+        assert_eq!(file.name, "example.iso");
+        assert_eq!(file.size, Some(2500));
+        assert_eq!(
+            file.urls,
+            [
+                "https://example.com/example.iso",
+                "http://mirror.example.com/example.iso",
+                "ftp://ftp.example.com/example.iso"
+            ]
+        );
+        // the strongest hash is kept
+        assert_eq!(
+            file.checksum.as_ref().unwrap().to_string(),
+            format!("sha256:{}", SHA256)
+        );
+        let segments = file.pieces.as_ref().unwrap().segments(2500);
+        let ranges: Vec<(u64, u64)> = segments.iter().map(|s| (s.start, s.end)).collect();
+        assert_eq!(ranges, [(0, 999), (1000, 1999), (2000, 2499)]);
+        assert_eq!(segments[2].hash, format!("sha1:{}", SHA1));
+    }
+
+    #[test]
+    fn parses_a_metalink_3() {
+        let files = parse(&format!(
+            r#"<metalink version="3.0" xmlns="http://www.metalinker.org/">
+ <files>
+  <file name="a.bin">
+   <size>10</size>
+   <verification><hash type="sha1">{sha1}</hash></verification>
+   <resources>
+    <url type="http" preference="10">http://slow.example.com/a.bin</url>
+    <url type="http" preference="90">http://fast.example.com/a.bin</url>
+   </resources>
+  </file>
+  <file name="b.bin"><resources><url>http://example.com/b.bin</url></resources></file>
+ </files>
+</metalink>"#,
+            sha1 = SHA1
+        ))
+        .unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(
+            files[0].urls,
+            [
+                "http://fast.example.com/a.bin",
+                "http://slow.example.com/a.bin"
+            ]
+        );
+        assert_eq!(
+            files[0].checksum.as_ref().unwrap().to_string(),
+            format!("sha1:{}", SHA1)
+        );
+        assert!(files[0].pieces.is_none());
+        assert_eq!(files[1].size, None);
+        assert!(files[1].checksum.is_none());
+    }
+
+    #[test]
+    fn skips_pieces_that_dont_cover_the_file() {
+        let files = parse(&format!(
+            r#"<metalink><file name="a.bin"><size>3000</size>
+              <pieces length="1000" type="sha-1"><hash>{sha1}</hash></pieces>
+              <url>http://example.com/a.bin</url></file></metalink>"#,
+            sha1 = SHA1
+        ))
+        .unwrap();
+        assert!(files[0].pieces.is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_documents() {
+        assert!(parse("<metalink></metalink>").is_err());
+        assert!(parse("<metalink><file name=\"a\"></file></metalink>").is_err());
+        assert!(parse(
+            "<metalink><file name=\"..\"><url>http://example.com/</url></file></metalink>"
+        )
+        .is_err());
+        assert!(parse("not xml").is_err());
+    }
+}
@@ -1,5 +1,18 @@
+pub mod checksum;
 pub mod client;
+mod cookies;
+pub mod credentials;
 pub mod daemon;
+pub mod hls;
+mod limiter;
+mod metalink;
+mod protocol;
+pub mod proxy;
+mod queue;
+mod resume;
+mod retry;
+pub mod schedule;
+mod speed;
 pub mod stream;
 pub mod types;
 mod worker;
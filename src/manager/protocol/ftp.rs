@@ -0,0 +1,181 @@
+use super::{Body, Transfer};
+use crate::manager::resume::Validators;
+use crate::manager::retry::DownloadError;
+use bytes::Bytes;
+use percent_encoding::percent_decode_str;
+use std::io::Read;
+use suppaftp::native_tls::TlsConnector;
+use suppaftp::types::FileType;
+use suppaftp::{FtpError, NativeTlsConnector, NativeTlsFtpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+use url::Url;
+
+const DEFAULT_PORT: u16 = 21;
+const CHUNK_SIZE: usize = 64 * 1024;
+// chunks read ahead of the writer, keeps the rate limits effective
+const BUFFERED_CHUNKS: usize = 4;
+
+struct Head {
+    offset: u64,
+    total: Option<u64>,
+    validators: Validators,
+}
+
+// the ftp client is blocking, so the transfer runs on a blocking thread and hands the chunks over
+pub async fn open(
+    url: &str,
+    offset: u64,
+    validators: &Validators,
+) -> Result<Transfer, DownloadError> {
+    let url = Url::parse(url).map_err(|e| format!("invalid url {}", e))?;
+    let validators = validators.clone();
+    let (head_sender, head_receiver) = oneshot::channel();
+    let (chunk_sender, chunk_receiver) = mpsc::channel(BUFFERED_CHUNKS);
+    task::spawn_blocking(move || retrieve(url, offset, validators, head_sender, chunk_sender));
+    let head = head_receiver
+        .await
+        .map_err(|_| DownloadError::from("the ftp transfer stopped unexpectedly"))??;
+    Ok(Transfer {
+        offset: head.offset,
+        total: head.total,
+        validators: head.validators,
+        body: Body::Ftp(chunk_receiver),
+    })
+}
+
+fn retrieve(
+    url: Url,
+    offset: u64,
+    validators: Validators,
+    head_sender: oneshot::Sender<Result<Head, DownloadError>>,
+    chunk_sender: mpsc::Sender<Result<Bytes, DownloadError>>,
+) {
+    let (mut ftp, path, head) = match start(&url, offset, &validators) {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = head_sender.send(Err(e));
+            return;
+        }
+    };
+    let mut stream = match ftp.retr_as_stream(&path) {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = head_sender.send(Err(error("couldn't start the transfer: ", e)));
+            return;
+        }
+    };
+    let expected = head.total.map(|t| t - head.offset);
+    if head_sender.send(Ok(head)).is_err() {
+        return;
+    }
+    let mut read = 0;
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        let n = match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                let _ = chunk_sender.blocking_send(Err(DownloadError {
+                    msg: format!("failed to download chunk: {}", e),
+                    transient: true,
+                    retry_after: None,
+                }));
+                return;
+            }
+        };
+        read += n as u64;
+        // fails once the download is cancelled and the receiver is dropped
+        if chunk_sender
+            .blocking_send(Ok(Bytes::copy_from_slice(&buf[..n])))
+            .is_err()
+        {
+            return;
+        }
+    }
+    if let Some(expected) = expected.filter(|e| read < *e) {
+        let _ = chunk_sender.blocking_send(Err(DownloadError {
+            msg: format!("the transfer ended {} bytes early", expected - read),
+            transient: true,
+            retry_after: None,
+        }));
+        return;
+    }
+    if let Err(e) = ftp.finalize_retr_stream(stream) {
+        let _ = chunk_sender.blocking_send(Err(error("failed to finish the transfer: ", e)));
+        return;
+    }
+    let _ = ftp.quit();
+}
+
+// logs in and positions the transfer, returns the connection and the path to retrieve
+fn start(
+    url: &Url,
+    offset: u64,
+    validators: &Validators,
+) -> Result<(NativeTlsFtpStream, String, Head), DownloadError> {
+    let host = url.host_str().ok_or("the url has no host")?;
+    let mut ftp = NativeTlsFtpStream::connect((host, url.port().unwrap_or(DEFAULT_PORT)))
+        .map_err(|e| error("couldn't connect: ", e))?;
+    // explicit tls, the control connection is upgraded with AUTH TLS
+    if url.scheme() == "ftps" {
+        let tls = TlsConnector::new().map_err(|e| format!("couldn't set up tls: {}", e))?;
+        ftp = ftp
+            .into_secure(NativeTlsConnector::from(tls), host)
+            .map_err(|e| error("couldn't secure the connection: ", e))?;
+    }
+    let (user, password) = match url.username() {
+        "" => ("anonymous".to_string(), "anonymous@".to_string()),
+        user => (decode(user), decode(url.password().unwrap_or(""))),
+    };
+    ftp.login(user, password)
+        .map_err(|e| error("couldn't log in: ", e))?;
+    ftp.transfer_type(FileType::Binary)
+        .map_err(|e| error("couldn't switch to binary mode: ", e))?;
+    // relative to the login directory like other clients do, a second slash makes it absolute
+    let path = decode(url.path().strip_prefix('/').unwrap_or(url.path()));
+    // both are optional extensions, resuming without them can't be checked
+    let total = ftp.size(&path).ok().map(|s| s as u64);
+    let current = Validators {
+        etag: "".into(),
+        last_modified: ftp.mdtm(&path).map(|t| t.to_string()).unwrap_or_default(),
+    };
+    let mut offset = offset;
+    if offset > 0 && (validators.changed(&current) || total.is_some_and(|t| offset > t)) {
+        println!("{} changed on the server, starting over", url);
+        offset = 0;
+    }
+    if offset > 0 {
+        if let Err(e) = ftp.resume_transfer(offset as usize) {
+            println!("{} can't be resumed, starting over: {}", url, e);
+            offset = 0;
+        }
+    }
+    Ok((
+        ftp,
+        path,
+        Head {
+            offset,
+            total,
+            validators: current,
+        },
+    ))
+}
+
+fn decode(s: &str) -> String {
+    percent_decode_str(s).decode_utf8_lossy().into()
+}
+
+// connection problems and 4xx replies are temporary, 5xx ones permanent
+fn error(context: &str, e: FtpError) -> DownloadError {
+    let transient = match &e {
+        FtpError::ConnectionError(_) => true,
+        FtpError::UnexpectedResponse(res) => (400..500).contains(&res.status.code()),
+        _ => false,
+    };
+    DownloadError {
+        msg: format!("{}{}", context, e),
+        transient,
+        retry_after: None,
+    }
+}
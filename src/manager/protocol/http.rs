@@ -0,0 +1,121 @@
+use super::{Body, Transfer};
+use crate::manager::credentials::Credentials;
+use crate::manager::resume::Validators;
+use crate::manager::retry::DownloadError;
+use reqwest::header::{CONTENT_LENGTH, CONTENT_RANGE, IF_RANGE, RANGE};
+use reqwest::{IntoUrl, RequestBuilder, Response, StatusCode};
+use std::sync::Arc;
+
+// the client of a job, answers 401 challenges with the credentials of the daemon
+#[derive(Clone)]
+pub struct Client {
+    inner: reqwest::Client,
+    credentials: Arc<Credentials>,
+}
+
+impl Client {
+    pub fn new(inner: reqwest::Client, credentials: Arc<Credentials>) -> Self {
+        Client { inner, credentials }
+    }
+    pub fn get<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+        self.inner.get(url)
+    }
+    // a host that asked before gets the answer right away, otherwise the request is sent again
+    // once challenged. a second 401 is returned as is
+    pub async fn send(&self, req: RequestBuilder) -> Result<Response, reqwest::Error> {
+        let mut req = req.build()?;
+        let retry = req.try_clone();
+        self.credentials.authorize(&mut req);
+        let res = self.inner.execute(req).await?;
+        if res.status() != StatusCode::UNAUTHORIZED {
+            return Ok(res);
+        }
+        let mut retry = match retry {
+            Some(r) if self.credentials.challenged(res.url(), res.headers()) => r,
+            _ => return Ok(res),
+        };
+        // the challenge comes from where the redirects ended
+        *retry.url_mut() = res.url().clone();
+        self.credentials.authorize(&mut retry);
+        self.inner.execute(retry).await
+    }
+}
+
+pub async fn open(
+    client: &Client,
+    url: &str,
+    mut offset: u64,
+    validators: &Validators,
+) -> Result<Transfer, DownloadError> {
+    let res = loop {
+        let mut req = client.get(url);
+        if offset > 0 {
+            println!("adding range header bytes={}-", offset);
+            req = req.header(RANGE, format!("bytes={}-", offset));
+            // the server sends the whole file instead if it changed
+            if let Some(v) = validators.if_range() {
+                req = req.header(IF_RANGE, v);
+            }
+        }
+        let res = client
+            .send(req)
+            .await
+            .map_err(|e| DownloadError::from_reqwest("", e))?;
+        // the file got shorter than what we have
+        if offset > 0 && res.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            println!("{} changed on the server, starting over", url);
+            offset = 0;
+            continue;
+        }
+        if let Some(e) = DownloadError::from_response(&res) {
+            return Err(e);
+        }
+        if offset > 0 && res.status() != StatusCode::PARTIAL_CONTENT {
+            println!("{} can't be resumed, starting over", url);
+            offset = 0;
+        } else if offset > 0 && validators.changed(&Validators::from_headers(res.headers())) {
+            println!("{} changed on the server, starting over", url);
+            offset = 0;
+            continue;
+        }
+        break res;
+    };
+    let total = res
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .map(|len| len + offset);
+    Ok(Transfer {
+        offset,
+        total,
+        validators: Validators::from_headers(res.headers()),
+        body: Body::Http(res),
+    })
+}
+
+// returns the total size and the validators if partial downloads are supported, the size is
+// 0 if the server didn't send it. with if_range a changed file counts as unsupported
+pub async fn check_partial_content_support(
+    client: &Client,
+    url: String,
+    if_range: Option<&str>,
+) -> Result<Option<(u64, Validators)>, reqwest::Error> {
+    // got empty response from a server while using head
+    let mut req = client.get(url).header(RANGE, "bytes=0-0");
+    if let Some(v) = if_range {
+        req = req.header(IF_RANGE, v);
+    }
+    let req = client.send(req).await?;
+    if req.status() != StatusCode::PARTIAL_CONTENT {
+        return Ok(None);
+    }
+    let total = req
+        .headers()
+        .get(CONTENT_RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.rsplit('/').next())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+    Ok(Some((total, Validators::from_headers(req.headers()))))
+}
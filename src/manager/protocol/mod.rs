@@ -0,0 +1,75 @@
+pub mod ftp;
+pub mod http;
+
+use super::resume::Validators;
+use super::retry::DownloadError;
+use crate::err::{ManagerError, ManagerErrorKind};
+use bytes::Bytes;
+use tokio::sync::mpsc;
+use url::Url;
+
+// how a download is fetched, picked by the scheme of its url
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    Http,
+    Ftp,
+}
+
+impl Protocol {
+    pub fn of(url: &str) -> Result<Self, ManagerError> {
+        match Url::parse(url)?.scheme() {
+            "http" | "https" => Ok(Protocol::Http),
+            "ftp" | "ftps" => Ok(Protocol::Ftp),
+            scheme => Err(ManagerError {
+                kind: ManagerErrorKind::UnsupportedProtocol,
+                msg: format!("{} urls aren't supported", scheme),
+            }),
+        }
+    }
+    // whether byte ranges can be fetched over parallel connections
+    pub fn segmented(&self) -> bool {
+        *self == Protocol::Http
+    }
+    // starts fetching the file at offset, or from the start if the file changed or can't be
+    // resumed. http requests go through the client of the job
+    pub async fn open(
+        &self,
+        client: &http::Client,
+        url: &str,
+        offset: u64,
+        validators: &Validators,
+    ) -> Result<Transfer, DownloadError> {
+        match self {
+            Protocol::Http => http::open(client, url, offset, validators).await,
+            Protocol::Ftp => ftp::open(url, offset, validators).await,
+        }
+    }
+}
+
+pub struct Transfer {
+    // where the body starts in the file, 0 if the download starts over
+    pub offset: u64,
+    // of the whole file, if the server told
+    pub total: Option<u64>,
+    pub validators: Validators,
+    body: Body,
+}
+
+enum Body {
+    Http(reqwest::Response),
+    // fed by a blocking task
+    Ftp(mpsc::Receiver<Result<Bytes, DownloadError>>),
+}
+
+impl Transfer {
+    // returns None once the whole body was read
+    pub async fn chunk(&mut self) -> Result<Option<Bytes>, DownloadError> {
+        match &mut self.body {
+            Body::Http(res) => res
+                .chunk()
+                .await
+                .map_err(|e| DownloadError::from_reqwest("failed to download chunk: ", e)),
+            Body::Ftp(chunks) => chunks.recv().await.transpose(),
+        }
+    }
+}
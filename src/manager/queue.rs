@@ -0,0 +1,83 @@
+use super::types::DownloadJob;
+use std::cmp::Reverse;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+// a job's place in the queue, shared with the daemon so it can be changed while the job waits
+#[derive(Clone, Debug)]
+pub struct QueueOrder {
+    inner: Arc<Mutex<(i32, i64)>>,
+}
+
+impl QueueOrder {
+    pub fn new(priority: i32, position: i64) -> Self {
+        QueueOrder {
+            inner: Arc::new(Mutex::new((priority, position))),
+        }
+    }
+    // the priority and the position among jobs of the same priority
+    pub fn get(&self) -> (i32, i64) {
+        *self.inner.lock().unwrap()
+    }
+    pub fn set(&self, priority: i32, position: i64) {
+        *self.inner.lock().unwrap() = (priority, position);
+    }
+}
+
+// the jobs waiting for a worker, higher priorities first then lower positions
+#[derive(Clone)]
+pub struct JobQueue {
+    jobs: Arc<Mutex<Vec<DownloadJob>>>,
+    notify: Arc<Notify>,
+    // jobs are held back while closed, e.g. outside the download windows
+    open: Arc<AtomicBool>,
+}
+
+impl JobQueue {
+    pub fn new() -> Self {
+        JobQueue {
+            jobs: Arc::new(Mutex::new(Vec::new())),
+            notify: Arc::new(Notify::new()),
+            open: Arc::new(AtomicBool::new(true)),
+        }
+    }
+    pub fn is_open(&self) -> bool {
+        self.open.load(Ordering::SeqCst)
+    }
+    pub fn set_open(&self, open: bool) {
+        self.open.store(open, Ordering::SeqCst);
+        if open {
+            self.notify.notify_one();
+        }
+    }
+    pub fn push(&self, job: DownloadJob) {
+        self.jobs.lock().unwrap().push(job);
+        self.notify.notify_one();
+    }
+    // waits until a job is available
+    pub async fn pop(&self) -> DownloadJob {
+        loop {
+            if let Some(job) = self.take() {
+                return job;
+            }
+            self.notify.notified().await;
+        }
+    }
+    fn take(&self) -> Option<DownloadJob> {
+        if !self.is_open() {
+            return None;
+        }
+        let mut jobs = self.jobs.lock().unwrap();
+        let (idx, _) = jobs.iter().enumerate().min_by_key(|(_, job)| {
+            let (priority, position) = job.order.get();
+            (Reverse(priority), position)
+        })?;
+        let job = jobs.remove(idx);
+        // a single wakeup is stored for several pushes, pass it on to the next worker
+        if !jobs.is_empty() {
+            self.notify.notify_one();
+        }
+        Some(job)
+    }
+}
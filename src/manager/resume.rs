@@ -0,0 +1,58 @@
+use crate::types::JobInfo;
+use reqwest::header::{HeaderMap, ETAG, LAST_MODIFIED};
+
+// identify the version of a remote file, a download is only resumed if they still match
+#[derive(Clone, Debug, Default)]
+pub struct Validators {
+    pub etag: String,
+    pub last_modified: String,
+}
+
+impl Validators {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let get = |name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default()
+                .to_string()
+        };
+        Validators {
+            etag: get(ETAG),
+            last_modified: get(LAST_MODIFIED),
+        }
+    }
+    pub fn save(&self, state: &mut JobInfo) {
+        state.etag = self.etag.clone();
+        state.last_modified = self.last_modified.clone();
+    }
+    // the value of If-Range, weak etags aren't allowed there
+    pub fn if_range(&self) -> Option<&str> {
+        if !self.etag.is_empty() && !self.etag.starts_with("W/") {
+            return Some(&self.etag);
+        }
+        if !self.last_modified.is_empty() {
+            return Some(&self.last_modified);
+        }
+        None
+    }
+    // only validators known on both sides are compared
+    pub fn changed(&self, other: &Validators) -> bool {
+        if !self.etag.is_empty() && !other.etag.is_empty() {
+            return self.etag != other.etag;
+        }
+        if !self.last_modified.is_empty() && !other.last_modified.is_empty() {
+            return self.last_modified != other.last_modified;
+        }
+        false
+    }
+}
+
+impl From<&JobInfo> for Validators {
+    fn from(state: &JobInfo) -> Self {
+        Validators {
+            etag: state.etag.clone(),
+            last_modified: state.last_modified.clone(),
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use super::queue::JobQueue;
+use super::types::DownloadJob;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Response, StatusCode};
+use std::time::{Duration, SystemTime};
+use tokio::time;
+
+const BASE_DELAY: Duration = Duration::from_secs(2);
+const MAX_DELAY: Duration = Duration::from_secs(300);
+// servers can ask for longer than our own backoff, but not forever
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(6 * 60 * 60);
+
+#[derive(Debug)]
+pub struct DownloadError {
+    pub msg: String,
+    // worth retrying, e.g. connection resets, timeouts and 5xx responses
+    pub transient: bool,
+    // how long the server asked us to wait before retrying
+    pub retry_after: Option<Duration>,
+}
+
+impl DownloadError {
+    pub fn from_reqwest(context: &str, e: reqwest::Error) -> Self {
+        DownloadError {
+            msg: format!("{}{}", context, e),
+            transient: e.is_connect()
+                || e.is_timeout()
+                || e.is_request()
+                || e.is_body()
+                || e.is_decode(),
+            retry_after: None,
+        }
+    }
+    // returns an error if the response status isn't a success
+    pub fn from_response(res: &Response) -> Option<Self> {
+        let status = res.status();
+        if status.is_success() {
+            return None;
+        }
+        Some(DownloadError {
+            msg: format!("server responded with {}", status),
+            transient: status.is_server_error()
+                || status == StatusCode::TOO_MANY_REQUESTS
+                || status == StatusCode::REQUEST_TIMEOUT,
+            retry_after: res
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_retry_after),
+        })
+    }
+}
+
+impl From<String> for DownloadError {
+    fn from(msg: String) -> Self {
+        DownloadError {
+            msg,
+            transient: false,
+            retry_after: None,
+        }
+    }
+}
+
+impl From<&str> for DownloadError {
+    fn from(msg: &str) -> Self {
+        msg.to_string().into()
+    }
+}
+
+// Retry-After is either a number of seconds or an http date
+fn parse_retry_after(v: &str) -> Option<Duration> {
+    let delay = match v.trim().parse() {
+        Ok(secs) => Duration::from_secs(secs),
+        Err(_) => {
+            let at = httpdate::parse_http_date(v.trim()).ok()?;
+            at.duration_since(SystemTime::now()).unwrap_or_default()
+        }
+    };
+    Some(delay.min(MAX_RETRY_AFTER))
+}
+
+// the delay before the given attempt is retried, doubling each time
+pub fn backoff(attempt: u32) -> Duration {
+    BASE_DELAY
+        .checked_mul(1 << attempt.saturating_sub(1).min(16))
+        .unwrap_or(MAX_DELAY)
+        .min(MAX_DELAY)
+}
+
+// puts the job back on the download queue after the delay unless it got cancelled meanwhile.
+// a job can be resumed before the worker of its previous run stopped, it waits for that worker
+// so that they don't both write the download
+pub fn requeue_after(queue: JobQueue, job: DownloadJob, delay: Duration) {
+    tokio::spawn(async move {
+        time::sleep(delay).await;
+        drop(job.running.lock().await);
+        if job.cancel_channel.borrow().cancel {
+            return;
+        }
+        queue.push(job);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // answers a single request with the given status line and headers
+    async fn respond(status: &str, headers: &str) -> Response {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let res = format!(
+            "HTTP/1.1 {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+            status, headers
+        );
+        tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let _ = conn.read(&mut buf).await;
+            conn.write_all(res.as_bytes()).await.unwrap();
+        });
+        reqwest::get(format!("http://{}/", addr)).await.unwrap()
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        assert_eq!(backoff(0), Duration::from_secs(2));
+        assert_eq!(backoff(1), Duration::from_secs(2));
+        assert_eq!(backoff(2), Duration::from_secs(4));
+        assert_eq!(backoff(5), Duration::from_secs(32));
+        assert_eq!(backoff(9), MAX_DELAY);
+        assert_eq!(backoff(u32::MAX), MAX_DELAY);
+    }
+
+    #[test]
+    fn parses_retry_after() {
+        assert_eq!(parse_retry_after(" 120 "), Some(Duration::from_secs(120)));
+        let at = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(600));
+        let delay = parse_retry_after(&at).unwrap();
+        assert!(delay > Duration::from_secs(590) && delay <= Duration::from_secs(600));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon"), None);
+        assert_eq!(parse_retry_after("31536000"), Some(MAX_RETRY_AFTER));
+    }
+
+    #[tokio::test]
+    async fn classifies_responses() {
+        assert!(DownloadError::from_response(&respond("200 OK", "").await).is_none());
+        let e = DownloadError::from_response(
+            &respond("503 Service Unavailable", "Retry-After: 7\r\n").await,
+        )
+        .unwrap();
+        assert!(e.transient);
+        assert_eq!(e.retry_after, Some(Duration::from_secs(7)));
+        for status in ["429 Too Many Requests", "408 Request Timeout"] {
+            let e = DownloadError::from_response(&respond(status, "").await).unwrap();
+            assert!(e.transient, "{}", status);
+            assert_eq!(e.retry_after, None);
+        }
+        for status in ["404 Not Found", "403 Forbidden"] {
+            let e = DownloadError::from_response(&respond(status, "").await).unwrap();
+            assert!(!e.transient, "{}", status);
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_connection_errors() {
+        // nothing listens on the port once the listener is dropped
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let e = reqwest::get(format!("http://{}/", addr)).await.unwrap_err();
+        assert!(DownloadError::from_reqwest("", e).transient);
+        assert!(!DownloadError::from("no space left").transient);
+    }
+}
@@ -0,0 +1,215 @@
+use crate::err::{ManagerError, ManagerErrorKind};
+use chrono::{DateTime, Days, Local, NaiveDateTime, NaiveTime, TimeZone};
+use std::fmt;
+use std::str::FromStr;
+
+const TIME_FORMATS: [&str; 2] = ["%H:%M", "%H:%M:%S"];
+const DATE_TIME_FORMATS: [&str; 4] = [
+    "%Y-%m-%d %H:%M",
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%dT%H:%M",
+    "%Y-%m-%dT%H:%M:%S",
+];
+
+// a daily range of local time, e.g. 01:00-07:00, wrapping around midnight if it ends before it starts
+#[derive(Clone, Copy, Debug)]
+pub struct Window {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl Window {
+    fn contains(&self, t: NaiveTime) -> bool {
+        match self.start.cmp(&self.end) {
+            std::cmp::Ordering::Less => t >= self.start && t < self.end,
+            std::cmp::Ordering::Greater => t >= self.start || t < self.end,
+            // the whole day
+            std::cmp::Ordering::Equal => true,
+        }
+    }
+}
+
+impl FromStr for Window {
+    type Err = ManagerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.trim().split_once('-').ok_or_else(|| ManagerError {
+            kind: ManagerErrorKind::InvalidTime,
+            msg: format!("expected a window like 01:00-07:00, got {}", s),
+        })?;
+        Ok(Window {
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+        })
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+// when downloads are allowed to run, at any time if there are no windows
+#[derive(Clone, Debug, Default)]
+pub struct Schedule {
+    windows: Vec<Window>,
+}
+
+impl Schedule {
+    pub fn is_open(&self, now: DateTime<Local>) -> bool {
+        self.windows.is_empty() || self.windows.iter().any(|w| w.contains(now.time()))
+    }
+}
+
+// comma separated windows, e.g. 01:00-07:00,22:00-23:30
+impl FromStr for Schedule {
+    type Err = ManagerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut windows = Vec::new();
+        for w in s.split(',').filter(|w| !w.trim().is_empty()) {
+            windows.push(w.parse()?);
+        }
+        Ok(Schedule { windows })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let windows: Vec<String> = self.windows.iter().map(|w| w.to_string()).collect();
+        write!(f, "{}", windows.join(","))
+    }
+}
+
+fn parse_time(s: &str) -> Result<NaiveTime, ManagerError> {
+    let s = s.trim();
+    for format in TIME_FORMATS.iter() {
+        if let Ok(t) = NaiveTime::parse_from_str(s, format) {
+            return Ok(t);
+        }
+    }
+    Err(ManagerError {
+        kind: ManagerErrorKind::InvalidTime,
+        msg: format!("expected a time like 07:30, got {}", s),
+    })
+}
+
+// parses when a download should start into a unix timestamp, accepts a local time of day
+// (the next time it comes), a local date and time or an rfc 3339 timestamp
+pub fn parse_start_time(s: &str) -> Result<u64, ManagerError> {
+    let s = s.trim();
+    let now = Local::now();
+    let at = if let Ok(t) = parse_time(s) {
+        let mut at = now.date_naive().and_time(t);
+        if at <= now.naive_local() {
+            at = at.checked_add_days(Days::new(1)).unwrap_or(at);
+        }
+        local(at, s)?
+    } else if let Some(at) = DATE_TIME_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+    {
+        local(at, s)?
+    } else {
+        DateTime::parse_from_rfc3339(s)
+            .map_err(|_| ManagerError {
+                kind: ManagerErrorKind::InvalidTime,
+                msg: format!(
+                    "expected a time like 01:30, 2022-01-31 01:30 or an rfc 3339 timestamp, got {}",
+                    s
+                ),
+            })?
+            .with_timezone(&Local)
+    };
+    Ok(at.timestamp().max(0) as u64)
+}
+
+fn local(at: NaiveDateTime, s: &str) -> Result<DateTime<Local>, ManagerError> {
+    // a time skipped by a daylight saving change doesn't exist
+    Local
+        .from_local_datetime(&at)
+        .earliest()
+        .ok_or_else(|| ManagerError {
+            kind: ManagerErrorKind::InvalidTime,
+            msg: format!("{} doesn't exist in the local time zone", s),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::err::ManagerErrorKind::InvalidTime;
+
+    fn time(s: &str) -> NaiveTime {
+        parse_time(s).unwrap()
+    }
+
+    fn today_at(s: &str) -> DateTime<Local> {
+        local(Local::now().date_naive().and_time(time(s)), s).unwrap()
+    }
+
+    #[test]
+    fn parses_windows() {
+        let w: Window = " 1:00-07:30:15 ".parse().unwrap();
+        assert_eq!(w.to_string(), "01:00-07:30");
+        for s in ["01:00", "25:00-07:00", "01:00-", "a-b"] {
+            assert_eq!(s.parse::<Window>().unwrap_err().kind, InvalidTime, "{}", s);
+        }
+    }
+
+    #[test]
+    fn wraps_around_midnight() {
+        let day: Window = "09:00-17:00".parse().unwrap();
+        assert!(day.contains(time("09:00")));
+        assert!(day.contains(time("16:59")));
+        assert!(!day.contains(time("17:00")));
+        assert!(!day.contains(time("23:00")));
+        let night: Window = "22:00-06:00".parse().unwrap();
+        assert!(night.contains(time("23:00")));
+        assert!(night.contains(time("00:00")));
+        assert!(night.contains(time("05:59")));
+        assert!(!night.contains(time("06:00")));
+        assert!(!night.contains(time("12:00")));
+        let all: Window = "03:00-03:00".parse().unwrap();
+        assert!(all.contains(time("12:00")));
+    }
+
+    #[test]
+    fn opens_in_any_window() {
+        assert!(Schedule::default().is_open(Local::now()));
+        let s: Schedule = "01:00-02:00, 22:00-06:00,".parse().unwrap();
+        assert_eq!(s.to_string(), "01:00-02:00,22:00-06:00");
+        assert!(s.is_open(today_at("01:30")));
+        assert!(s.is_open(today_at("23:00")));
+        assert!(!s.is_open(today_at("12:00")));
+        assert!("01:00-02:00,later".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn parses_start_times() {
+        assert_eq!(
+            parse_start_time("2030-01-31T01:30:00Z").unwrap(),
+            1896053400
+        );
+        let at = local(
+            NaiveDateTime::parse_from_str("2030-01-31 01:30", "%Y-%m-%d %H:%M").unwrap(),
+            "",
+        )
+        .unwrap();
+        assert_eq!(
+            parse_start_time("2030-01-31 01:30").unwrap(),
+            at.timestamp() as u64
+        );
+        // a time of day is the next time it comes
+        let now = Local::now().timestamp() as u64;
+        let at = parse_start_time("07:30").unwrap();
+        assert!(at > now && at <= now + 25 * 60 * 60);
+        assert_eq!(parse_start_time("tomorrow").unwrap_err().kind, InvalidTime);
+    }
+}
@@ -0,0 +1,38 @@
+use std::time::{Duration, Instant};
+
+// how often a new sample is taken
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+// weight of the newest sample, the rest comes from the previous average
+const SMOOTHING: f64 = 0.3;
+
+// an exponential moving average of the download rate, so the eta doesn't jump around
+pub struct SpeedMeter {
+    last: Instant,
+    bytes: u64,
+    speed: Option<f64>,
+}
+
+impl SpeedMeter {
+    pub fn new() -> Self {
+        SpeedMeter {
+            last: Instant::now(),
+            bytes: 0,
+            speed: None,
+        }
+    }
+    // records n downloaded bytes and returns the current speed in bytes per second
+    pub fn update(&mut self, n: u64) -> u64 {
+        self.bytes += n;
+        let elapsed = self.last.elapsed();
+        if elapsed >= SAMPLE_INTERVAL {
+            let sample = self.bytes as f64 / elapsed.as_secs_f64();
+            self.speed = Some(match self.speed {
+                Some(speed) => SMOOTHING * sample + (1.0 - SMOOTHING) * speed,
+                None => sample,
+            });
+            self.bytes = 0;
+            self.last = Instant::now();
+        }
+        self.speed.unwrap_or(0.0) as u64
+    }
+}
@@ -1,24 +1,163 @@
+use super::checksum::Checksum;
+use super::cookies::{self, CookieJar, JobCookies};
+use super::credentials::Credentials;
+use super::hls::Variant;
+use super::limiter::RateLimiter;
+use super::protocol::{http, Protocol};
+use super::proxy::{self, Proxies};
+use super::queue::QueueOrder;
+use super::resume::Validators;
+use super::retry::DownloadError;
 use crate::err::ManagerError;
-use crate::types::JobInfo;
+use crate::types::{unix_now, JobInfo, RequestOptions, State};
 use serde::Deserialize;
 use serde::Serialize;
 use std::fmt;
 use std::fmt::Display;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
 use tokio::sync::watch;
+use url::Url;
 
 pub struct DownloadJob {
     pub name: String,
     pub url: String,
+    pub mirrors: Vec<String>,
+    pub protocol: Protocol,
+    // set for hls playlists
+    pub hls: Option<Variant>,
     pub file_path: PathBuf,
+    // where the download is written until it's complete
+    pub part_path: PathBuf,
+    pub segments: u32,
+    pub checksum: Option<Checksum>,
+    // attempts made so far, the worker counts the one it's starting
+    pub attempts: u32,
+    pub max_attempts: u32,
+    pub limiter: RateLimiter,
+    // kept across retries and pauses, 0 if the download never started
+    pub started_at: u64,
+    pub order: QueueOrder,
+    // of the remote file the partial download came from
+    pub validators: Validators,
+    pub request: RequestOptions,
+    pub cookies: Arc<CookieJar>,
+    // only sent by this job's requests
+    pub cookies_txt: String,
+    pub proxies: Proxies,
+    pub credentials: Arc<Credentials>,
     pub cancel_channel: watch::Receiver<CancelInfo>,
+    // locked by the worker running the job, shared by all the runs of a job
+    pub running: Arc<tokio::sync::Mutex<()>>,
+    // given by the worker running the job, tags its progress reports
+    pub run: u64,
+}
+
+impl DownloadJob {
+    // sends the request options of the job along with every request through the proxy for its
+    // url, keeps the cookies sites set in the jar and logs in to the hosts that ask
+    pub fn client(&self) -> Result<http::Client, DownloadError> {
+        let headers = self
+            .request
+            .header_map()
+            .map_err(|e| DownloadError::from(e.msg))?;
+        let (proxies, job_proxy) = (self.proxies.clone(), self.request.proxy.clone());
+        reqwest::Client::builder()
+            .proxy(reqwest::Proxy::custom(move |url| {
+                proxies.choose(&job_proxy, url)
+            }))
+            .default_headers(headers)
+            .cookie_provider(Arc::new(JobCookies {
+                jar: self.cookies.clone(),
+                extra: self.request.cookies.clone(),
+                // checked when the job was added
+                own: cookies::parse(&self.cookies_txt).unwrap_or_default(),
+            }))
+            .build()
+            .map(|c| http::Client::new(c, self.credentials.clone()))
+            .map_err(|e| DownloadError::from_reqwest("couldn't create the http client: ", e))
+    }
+    // the proxy of the url without its password, empty if there's none. ftp doesn't use them
+    pub fn used_proxy(&self) -> String {
+        match Url::parse(&self.url) {
+            Ok(url) if self.protocol == Protocol::Http => self
+                .proxies
+                .choose(&self.request.proxy, &url)
+                .map(|p| proxy::redact(&p))
+                .unwrap_or_default(),
+            _ => "".into(),
+        }
+    }
+}
+
+// <name>.part next to the final path, or in the directory for incomplete downloads if one is set
+pub fn part_path(path: &Path, incomplete: &str) -> PathBuf {
+    let name = format!(
+        "{}.part",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    );
+    match incomplete {
+        "" => path.with_file_name(name),
+        dir => Path::new(dir).join(name),
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AddCommand {
     pub url: String,
     pub name: Option<String>,
+    pub segments: Option<u32>,
+    pub checksum: Option<String>,
+    pub max_attempts: Option<u32>,
+    // bytes per second
+    pub rate_limit: Option<u64>,
+    pub priority: Option<i32>,
+    // unix timestamp
+    pub start_at: Option<u64>,
+    // a metalink document to add the files of, the url isn't used then
+    pub metalink: Option<String>,
+    // the url is an hls playlist even though it doesn't end with .m3u8
+    #[serde(default)]
+    pub hls: bool,
+    // best, worst or the highest line count like 720p, implies hls
+    pub variant: Option<String>,
+    #[serde(default)]
+    pub request: RequestOptions,
+    // a netscape cookies.txt only sent by the requests of the job
+    pub cookies_txt: Option<String>,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AddBatchCommand {
+    pub jobs: Vec<AddCommand>,
+}
+
+// the outcome of each job of a batch, in the order they were given
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AddBatchResponse {
+    pub results: Vec<AddResult>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AddResult {
+    pub url: String,
+    // none if the job was added
+    pub error: Option<String>,
+}
+
+impl Display for AddBatchResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmt::Result {
+        for r in self.results.iter() {
+            match &r.error {
+                None => writeln!(f, "- {}: added", r.url)?,
+                Some(e) => writeln!(f, "- {}: {}", r.url, e)?,
+            }
+        }
+        let added = self.results.iter().filter(|r| r.error.is_none()).count();
+        write!(f, "added {} of {}", added, self.results.len())
+    }
 }
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CancelCommand {
     pub name: String,
@@ -27,8 +166,53 @@ pub struct CancelCommand {
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct ErrorCommand {
-    pub msg: String,
+pub struct PauseCommand {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResumeCommand {
+    pub name: String,
+}
+
+// sets the limit of a single job, or the global one if no name is given
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LimitCommand {
+    pub name: Option<String>,
+    pub rate_limit: u64,
+}
+
+// a login for the downloads from a host, or host:port, the password stays in memory
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CredentialsCommand {
+    pub host: String,
+    pub user: String,
+    pub password: String,
+}
+
+impl fmt::Debug for CredentialsCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "CredentialsCommand {{ host: {}, user: {} }}",
+            self.host, self.user
+        )
+    }
+}
+
+// moves a waiting job to another place in the queue
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Reorder {
+    Priority(i32),
+    Front,
+    Back,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReorderCommand {
+    pub name: String,
+    pub reorder: Reorder,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -45,17 +229,92 @@ pub struct InfoResponse {
     pub total: u64,
     pub state: String, // should it be State?
     pub msg: String,
+    pub segments: u32,
+    pub checksum: String,
+    pub attempts: u32,
+    pub max_attempts: u32,
+    pub next_retry: u64,
+    pub rate_limit: u64,
+    pub speed: u64,
+    // seconds left at the current speed, 0 if unknown
+    pub eta: u64,
+    pub started_at: u64,
+    pub finished_at: u64,
+    pub priority: i32,
+    pub position: i64,
+    pub start_at: u64,
+    pub mirrors: Vec<String>,
+    pub variant: String,
+    pub proxy: String,
 }
 impl Display for InfoResponse {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "name: {}", self.name)?;
         writeln!(f, "url: {}", self.url)?;
+        for mirror in self.mirrors.iter() {
+            writeln!(f, "mirror: {}", mirror)?;
+        }
         writeln!(f, "path: {}", self.path)?;
+        if !self.proxy.is_empty() {
+            writeln!(f, "proxy: {}", self.proxy)?;
+        }
         writeln!(f, "downloaded: {}", self.downloaded)?;
         if self.total != 0 {
             writeln!(f, "total: {}", self.total)?;
         }
         writeln!(f, "state: {}", self.state)?;
+        if self.priority != 0 {
+            writeln!(f, "priority: {}", self.priority)?;
+        }
+        if self.segments > 1 {
+            writeln!(f, "segments: {}", self.segments)?;
+        }
+        if !self.variant.is_empty() {
+            writeln!(f, "hls variant: {}", self.variant)?;
+        }
+        if !self.checksum.is_empty() {
+            writeln!(f, "checksum: {}", self.checksum)?;
+        }
+        if self.attempts != 0 {
+            writeln!(f, "attempts: {}/{}", self.attempts, self.max_attempts)?;
+        }
+        if self.next_retry > unix_now() {
+            writeln!(
+                f,
+                "next retry: in {}s",
+                self.next_retry.saturating_sub(unix_now())
+            )?;
+        }
+        if self.rate_limit != 0 {
+            writeln!(f, "rate limit: {} B/s", self.rate_limit)?;
+        }
+        if self.speed != 0 {
+            writeln!(f, "speed: {}/s", human_bytes(self.speed))?;
+        }
+        if self.eta != 0 {
+            writeln!(f, "eta: {}", human_duration(self.eta))?;
+        }
+        if self.start_at > unix_now() {
+            writeln!(
+                f,
+                "starts at: {} (in {})",
+                human_time(self.start_at),
+                human_duration(self.start_at.saturating_sub(unix_now()))
+            )?;
+        }
+        if self.started_at != 0 {
+            writeln!(f, "started: {}", human_time(self.started_at))?;
+        }
+        if self.finished_at != 0 {
+            writeln!(f, "finished: {}", human_time(self.finished_at))?;
+            if self.started_at != 0 {
+                writeln!(
+                    f,
+                    "took: {}",
+                    human_duration(self.finished_at.saturating_sub(self.started_at))
+                )?;
+            }
+        }
         if !self.msg.is_empty() {
             writeln!(f, "msg: {}", self.msg)?;
         }
@@ -72,6 +331,27 @@ impl From<&JobInfo> for InfoResponse {
             total: s.total,
             state: s.state.to_string(),
             msg: s.msg.clone(),
+            segments: s.segments,
+            checksum: s.checksum.clone(),
+            attempts: s.attempts,
+            max_attempts: s.max_attempts,
+            next_retry: s.next_retry,
+            rate_limit: s.rate_limit,
+            // the last speed of a stopped job is stale
+            speed: if s.state == State::Active { s.speed } else { 0 },
+            eta: if s.state == State::Active && s.speed != 0 && s.total > s.downloaded {
+                (s.total - s.downloaded) / s.speed
+            } else {
+                0
+            },
+            started_at: s.started_at,
+            finished_at: s.finished_at,
+            priority: s.priority,
+            position: s.position,
+            start_at: s.start_at,
+            mirrors: s.mirrors.clone(),
+            variant: s.variant.clone(),
+            proxy: s.used_proxy.clone(),
         }
     }
 }
@@ -83,10 +363,41 @@ impl Display for ListResponse {
         for e in self.0.iter() {
             write!(f, "- {}:", e.name)?;
             write!(f, " [{}]", e.state)?;
+            if e.priority != 0 {
+                write!(f, " [priority {}]", e.priority)?;
+            }
             if e.total != 0 {
-                write!(f, " [{}/{}]", e.downloaded, e.total)?;
+                write!(
+                    f,
+                    " [{}/{}]",
+                    human_bytes(e.downloaded),
+                    human_bytes(e.total)
+                )?;
             } else {
-                write!(f, " [{}]", e.downloaded)?;
+                write!(f, " [{}]", human_bytes(e.downloaded))?;
+            }
+            if e.speed != 0 {
+                write!(f, " [{}/s", human_bytes(e.speed))?;
+                if e.eta != 0 {
+                    write!(f, ", {} left", human_duration(e.eta))?;
+                }
+                write!(f, "]")?;
+            }
+            if e.start_at > unix_now() {
+                write!(
+                    f,
+                    " [starts in {}]",
+                    human_duration(e.start_at.saturating_sub(unix_now()))
+                )?;
+            }
+            if e.next_retry > unix_now() {
+                write!(
+                    f,
+                    " [retry {}/{} in {}s]",
+                    e.attempts + 1,
+                    e.max_attempts,
+                    e.next_retry.saturating_sub(unix_now())
+                )?;
             }
             writeln!(f)?;
         }
@@ -103,6 +414,71 @@ impl From<Vec<JobInfo>> for ListResponse {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Added,
+    Started,
+    Progress,
+    Retrying,
+    Paused,
+    Resumed,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl Display for EventKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                EventKind::Added => "added",
+                EventKind::Started => "started",
+                EventKind::Progress => "progress",
+                EventKind::Retrying => "retrying",
+                EventKind::Paused => "paused",
+                EventKind::Resumed => "resumed",
+                EventKind::Done => "done",
+                EventKind::Failed => "failed",
+                EventKind::Cancelled => "cancelled",
+            }
+        )
+    }
+}
+
+// a job lifecycle event, carrying the job info at the time it happened
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Event {
+    pub kind: EventKind,
+    pub job: InfoResponse,
+}
+
+impl Event {
+    pub fn new(kind: EventKind, job: &JobInfo) -> Self {
+        Event {
+            kind,
+            job: InfoResponse::from(job),
+        }
+    }
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}", self.kind, self.job.name)?;
+        if self.job.total != 0 {
+            write!(f, " [{}/{}]", self.job.downloaded, self.job.total)?;
+        } else {
+            write!(f, " [{}]", self.job.downloaded)?;
+        }
+        if !self.job.msg.is_empty() {
+            write!(f, " {}", self.job.msg)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ListCommand;
 
@@ -112,11 +488,18 @@ pub struct AckCommand;
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
     Add(AddCommand),
+    AddBatch(AddBatchCommand),
+    AddBatchResponse(AddBatchResponse),
     List(ListCommand),
     ListResponse(ListResponse),
     Info(InfoCommand),
     InfoResponse(InfoResponse),
     Cancel(CancelCommand),
+    Pause(PauseCommand),
+    Resume(ResumeCommand),
+    Limit(LimitCommand),
+    Reorder(ReorderCommand),
+    Credentials(CredentialsCommand),
     Ack(AckCommand),
     Error(ManagerError),
 }
@@ -132,3 +515,29 @@ impl Display for CancelInfo {
         writeln!(f, "cancel: {}, delete: {}", self.cancel, self.delete)
     }
 }
+
+fn human_bytes(n: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if n < 1024 {
+        return format!("{} B", n);
+    }
+    let mut v = n as f64 / 1024.0;
+    let mut unit = 0;
+    while v >= 1024.0 && unit < UNITS.len() - 1 {
+        v /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", v, UNITS[unit])
+}
+
+fn human_duration(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m{:02}s", secs / 3600, secs / 60 % 60, secs % 60),
+    }
+}
+
+fn human_time(unix: u64) -> String {
+    httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(unix))
+}
@@ -1,34 +1,79 @@
+use super::checksum::{hash_file, hash_range, Checksum};
+use super::hls::{self, MediaSegment, Variant};
+use super::limiter::RateLimiter;
+use super::protocol::{http, Protocol};
+use super::queue::JobQueue;
+use super::resume::Validators;
+use super::retry::{backoff, requeue_after, DownloadError};
+use super::speed::SpeedMeter;
 use super::types::CancelInfo;
-use super::types::DownloadJob;
+use super::types::{part_path, DownloadJob};
+use super::types::{Event, EventKind};
+use crate::err::ManagerError;
+use crate::err::ManagerErrorKind::DownloadJobNameAlreadyExist;
 use crate::jobs::client::StateClient;
-use crate::jobs::types::JobMessage;
-use crate::types::{JobInfo, State};
-use reqwest::header::RANGE;
+use crate::types::{unix_now, JobInfo, Segment, State};
+use reqwest::header::{IF_RANGE, RANGE};
 use reqwest::StatusCode;
-use std::io::ErrorKind;
+use std::collections::{HashMap, VecDeque};
+use std::io::{ErrorKind, SeekFrom};
 use std::os::unix::fs::MetadataExt;
-use tokio::fs::{metadata, OpenOptions};
-use tokio::io::AsyncWriteExt;
-use tokio::sync::watch;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::fs::{
+    copy, create_dir_all, metadata, remove_dir_all, remove_file, rename, File, OpenOptions,
+};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{broadcast, mpsc, watch};
+
+// segments smaller than this aren't worth a connection of their own
+const MIN_SEGMENT_SIZE: u64 = 1024 * 1024;
+// progress is reported on every chunk and saved in batches, events are sent at most once per
+// interval
+const PROGRESS_EVENT_INTERVAL: Duration = Duration::from_millis(500);
+
+enum SegmentEvent {
+    // segment index and the number of bytes written
+    Progress(u32, u64),
+    Finished(u32),
+    Failed(u32, DownloadError),
+}
 
 pub struct DownloadWorker {
-    job_receiver: async_channel::Receiver<DownloadJob>,
+    // also used to put jobs back when retrying
+    queue: JobQueue,
     state_client: StateClient,
+    // shared by all the workers
+    global_limiter: RateLimiter,
+    events: broadcast::Sender<Event>,
+    last_progress_event: Mutex<Instant>,
 }
 
 impl DownloadWorker {
     pub fn new(
-        job_receiver: async_channel::Receiver<DownloadJob>,
-        state_sender: async_channel::Sender<JobMessage>,
+        queue: JobQueue,
+        state_client: StateClient,
+        global_limiter: RateLimiter,
+        events: broadcast::Sender<Event>,
     ) -> Self {
         DownloadWorker {
-            job_receiver,
-            state_client: StateClient::new(state_sender),
+            queue,
+            state_client,
+            global_limiter,
+            events,
+            last_progress_event: Mutex::new(Instant::now()),
         }
     }
 
     pub async fn work(self) {
-        while let Ok(job) = self.job_receiver.recv().await {
+        loop {
+            let mut job = self.queue.pop().await;
+            // held until the worker is done with the job, see requeue_after
+            let running = job.running.clone();
+            let _running = running.lock().await;
+            job.run = self.state_client.start(&job.name);
+            let (priority, position) = job.order.get();
             let mut state = JobInfo {
                 name: job.name.clone(),
                 url: job.url.clone(),
@@ -41,18 +86,93 @@ impl DownloadWorker {
                 total: 0,
                 state: State::Active,
                 msg: "".into(),
+                segments: job.segments,
+                checksum: job
+                    .checksum
+                    .as_ref()
+                    .map(|c| c.to_string())
+                    .unwrap_or_default(),
+                attempts: job.attempts + 1,
+                max_attempts: job.max_attempts,
+                next_retry: 0,
+                rate_limit: job.limiter.rate(),
+                speed: 0,
+                started_at: if job.started_at == 0 {
+                    unix_now()
+                } else {
+                    job.started_at
+                },
+                finished_at: 0,
+                priority,
+                position,
+                // only matters until the job starts
+                start_at: 0,
+                etag: job.validators.etag.clone(),
+                last_modified: job.validators.last_modified.clone(),
+                mirrors: job.mirrors.clone(),
+                variant: job.hls.map(|v| v.to_string()).unwrap_or_default(),
+                request: job.request.clone(),
+                used_proxy: job.used_proxy(),
+                cookies_txt: job.cookies_txt.clone(),
             };
+            // only a job that ran before can have left a download at the final path
+            let resumed = job.started_at != 0;
+            job.started_at = state.started_at;
 
-            if let Some(err) = self.download(&job, &mut state).await {
-                state.state = State::Failed;
-                state.msg = err;
-                self.update_state(state, &job.cancel_channel).await;
+            if !job.cancel_channel.borrow().cancel {
+                self.emit(EventKind::Started, &state);
+            }
+            let res = self.download(&job, &mut state, resumed).await;
+            if job.cancel_channel.borrow().cancel {
+                // paused or cancelled, the daemon already saved the state
+                self.state_client.discard(&job.name, job.run);
+            }
+            if let Some(err) = res {
+                if err.transient && state.attempts < state.max_attempts {
+                    let delay = err.retry_after.unwrap_or_else(|| backoff(state.attempts));
+                    println!("retrying {} in {:?}: {}", job.name, delay, err.msg);
+                    state.state = State::Pending;
+                    state.msg = err.msg;
+                    state.next_retry = unix_now().saturating_add(delay.as_secs());
+                    self.update_state(state.clone(), &job).await;
+                    job.attempts = state.attempts;
+                    job.validators = Validators::from(&state);
+                    requeue_after(self.queue.clone(), job, delay);
+                } else {
+                    state.state = State::Failed;
+                    state.msg = err.msg;
+                    self.update_state(state, &job).await;
+                }
             }
         }
-        println!("worker died!!!");
     }
-    async fn update_state(&self, state: JobInfo, cancelled: &watch::Receiver<CancelInfo>) {
-        if cancelled.borrow().cancel {
+    async fn update_state(&self, mut state: JobInfo, job: &DownloadJob) {
+        if job.cancel_channel.borrow().cancel {
+            return;
+        }
+        // the limit and the queue order can be changed at runtime, don't overwrite them with stale ones
+        state.rate_limit = job.limiter.rate();
+        let (priority, position) = job.order.get();
+        state.priority = priority;
+        state.position = position;
+        if state.state != State::Active {
+            state.speed = 0;
+        }
+        if (state.state == State::Done || state.state == State::Failed) && state.finished_at == 0 {
+            state.finished_at = unix_now();
+        }
+        let kind = match state.state {
+            State::Active => Some(EventKind::Progress),
+            State::Pending => Some(EventKind::Retrying),
+            State::Done => Some(EventKind::Done),
+            State::Failed => Some(EventKind::Failed),
+            _ => None,
+        };
+        if let Some(kind) = kind {
+            self.emit(kind, &state);
+        }
+        if state.state == State::Active {
+            self.state_client.report(state, job.run);
             return;
         }
         let res = self.state_client.update(state).await;
@@ -60,101 +180,852 @@ impl DownloadWorker {
             println!("failed to update state {}", e)
         }
     }
-    async fn check_partial_content_support(url: String) -> Result<bool, reqwest::Error> {
-        // got empty response from a server while using head
-        let req = reqwest::Client::new()
-            .get(url)
-            .header(RANGE, "bytes=0-0")
-            .send()
-            .await?;
-        Ok(req.status() == StatusCode::PARTIAL_CONTENT)
+    fn emit(&self, kind: EventKind, state: &JobInfo) {
+        if kind == EventKind::Progress {
+            let mut last = self.last_progress_event.lock().unwrap();
+            if last.elapsed() < PROGRESS_EVENT_INTERVAL {
+                return;
+            }
+            *last = Instant::now();
+        }
+        // fails only if nobody is listening
+        let _ = self.events.send(Event::new(kind, state));
+    }
+    fn update_segment(&self, job: &DownloadJob, segment: Segment) {
+        if job.cancel_channel.borrow().cancel {
+            return;
+        }
+        self.state_client
+            .report_segment(&job.name, job.run, segment);
+    }
+    fn split(total: u64, segments: u32) -> Vec<Segment> {
+        let count = (segments as u64).min(total / MIN_SEGMENT_SIZE).max(1);
+        let size = total / count;
+        (0..count)
+            .map(|i| Segment {
+                idx: i as u32,
+                start: i * size,
+                end: if i == count - 1 {
+                    total - 1
+                } else {
+                    (i + 1) * size - 1
+                },
+                downloaded: 0,
+                hash: "".into(),
+            })
+            .collect()
     }
     // returns an error message if something bad happened
-    async fn download(&self, job: &DownloadJob, state: &mut JobInfo) -> Option<String> {
+    async fn download(
+        &self,
+        job: &DownloadJob,
+        state: &mut JobInfo,
+        resumed: bool,
+    ) -> Option<DownloadError> {
         if job.cancel_channel.borrow().cancel {
             return None;
         }
-        let mut req = reqwest::Client::new().get(job.url.clone());
-
-        let file_metadata = metadata(&job.file_path).await;
-        match &file_metadata {
-            Err(e) => {
-                if e.kind() == ErrorKind::NotFound {
-                    // new download
-                } else {
-                    return Some(format!("couldn't stat download path {}", e.to_string()));
-                }
+        // downloads started by older versions were written to the final path directly, for a
+        // new job a file there is someone else's and is left alone
+        if metadata(&job.part_path).await.is_err() && metadata(&job.file_path).await.is_ok() {
+            if !resumed {
+                let e = ManagerError {
+                    kind: DownloadJobNameAlreadyExist,
+                    msg: format!("{} already exists", job.file_path.display()),
+                };
+                return Some(e.to_string().into());
             }
-            Ok(v) => match Self::check_partial_content_support(job.url.clone()).await {
+            if let Err(e) = rename(&job.file_path, &job.part_path).await {
+                return Some(format!("couldn't move the partial download aside: {}", e).into());
+            }
+        }
+        if let Some(variant) = job.hls {
+            return self.download_hls(job, state, variant).await;
+        }
+        // metalink mirrors can serve pieces even if the first url is ftp
+        let segments = if job.protocol.segmented() || !job.mirrors.is_empty() {
+            match self.state_client.get_segments(&job.name).await {
+                Ok(v) => v,
                 Err(e) => {
-                    return Some(format!(
-                        "couldn't check url support for partial downloads {}",
-                        e
-                    ));
-                }
-                Ok(supported) => {
-                    if supported {
-                        println!("adding range header {}", format!("bytes={}-", v.size()));
-                        req = req.header(RANGE, format!("bytes={}-", v.size()));
-                        state.downloaded = v.size();
-                    } else {
-                        return Some("remote url doesn't support partial downloads".to_string());
+                    return Some(format!("couldn't load the segments of the download {}", e).into())
+                }
+            }
+        } else {
+            Vec::new()
+        };
+        // metalink pieces have hashes of their own, they're checked instead of the validators
+        if segments.iter().any(|s| !s.hash.is_empty()) {
+            return self.download_pieces(job, state, segments).await;
+        }
+        if job.segments > 1 && job.protocol.segmented() {
+            let client = match job.client() {
+                Ok(v) => v,
+                Err(e) => return Some(e),
+            };
+            let mut exists = metadata(&job.part_path).await.is_ok();
+            if !segments.is_empty() && exists {
+                let total = segments.last().map(|s| s.end + 1).unwrap_or(0);
+                match http::check_partial_content_support(
+                    &client,
+                    job.url.clone(),
+                    job.validators.if_range(),
+                )
+                .await
+                {
+                    Ok(Some((t, v))) if (t == 0 || t == total) && !job.validators.changed(&v) => {
+                        return self.download_segmented(job, state, segments).await;
+                    }
+                    Ok(_) => {
+                        println!("{} changed on the server, starting over", job.name);
+                        if let Err(e) = remove_file(&job.part_path).await {
+                            return Some(
+                                format!("couldn't remove the outdated download {}", e).into(),
+                            );
+                        }
+                        exists = false;
+                    }
+                    Err(e) => {
+                        return Some(DownloadError::from_reqwest(
+                            "couldn't check whether the download can be resumed ",
+                            e,
+                        ))
                     }
                 }
-            },
+            }
+            if !segments.is_empty() {
+                // the partial file is gone, start over
+                if let Err(e) = self.state_client.delete_segments(&job.name).await {
+                    return Some(
+                        format!("couldn't reset the segments of the download {}", e).into(),
+                    );
+                }
+            }
+            if !exists {
+                match http::check_partial_content_support(&client, job.url.clone(), None).await {
+                    Ok(Some((total, validators))) if total > 0 => {
+                        let segments = Self::split(total, job.segments);
+                        if segments.len() > 1 {
+                            validators.save(state);
+                            return self.start_segmented(job, state, total, segments).await;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => println!(
+                        "couldn't check url support for partial downloads, using a single connection {}",
+                        e
+                    ),
+                }
+            }
         }
-        self.update_state(state.clone(), &job.cancel_channel).await;
-        let res = req.send().await;
-
-        if let Err(e) = res {
-            return Some(e.to_string());
+        self.download_stream(job, state).await
+    }
+    async fn download_pieces(
+        &self,
+        job: &DownloadJob,
+        state: &mut JobInfo,
+        mut segments: Vec<Segment>,
+    ) -> Option<DownloadError> {
+        if metadata(&job.part_path).await.is_ok() {
+            return self.download_segmented(job, state, segments).await;
         }
-        let mut res = res.unwrap();
-        let headers = res.headers();
-        if let Some(len_str) = headers.get("Content-Length") {
-            let len = len_str.to_str().unwrap_or("0").parse().unwrap_or(0);
-            state.total = len + file_metadata.map(|v| v.size()).unwrap_or(0);
-            self.update_state(state.clone(), &job.cancel_channel).await;
+        // the partial file is gone, start over
+        for segment in segments.iter_mut() {
+            segment.downloaded = 0;
         }
+        let total = segments.last().map(|s| s.end + 1).unwrap_or(0);
+        self.start_segmented(job, state, total, segments).await
+    }
+    async fn start_segmented(
+        &self,
+        job: &DownloadJob,
+        state: &mut JobInfo,
+        total: u64,
+        segments: Vec<Segment>,
+    ) -> Option<DownloadError> {
         let file = OpenOptions::new()
-            .append(true)
+            .write(true)
             .create(true)
-            .open(&job.file_path)
+            .truncate(true)
+            .open(&job.part_path)
             .await;
         if let Err(e) = file {
-            return Some(format!("failed to create file: {}", e.to_string()));
+            return Some(format!("failed to create file: {}", e).into());
+        }
+        if let Err(e) = file.unwrap().set_len(total).await {
+            return Some(format!("failed to allocate file: {}", e).into());
+        }
+        if let Err(e) = self
+            .state_client
+            .update_segments(&job.name, segments.clone())
+            .await
+        {
+            return Some(format!("couldn't save the segments of the download {}", e).into());
+        }
+        self.download_segmented(job, state, segments).await
+    }
+    async fn download_segmented(
+        &self,
+        job: &DownloadJob,
+        state: &mut JobInfo,
+        mut segments: Vec<Segment>,
+    ) -> Option<DownloadError> {
+        state.total = segments.iter().map(|s| s.end - s.start + 1).sum();
+        state.downloaded = segments.iter().map(|s| s.downloaded).sum();
+        self.update_state(state.clone(), job).await;
+
+        let client = match job.client() {
+            Ok(v) => v,
+            Err(e) => return Some(e),
+        };
+        let validators = Validators::from(&*state);
+        let pieces = segments.iter().any(|s| !s.hash.is_empty());
+        // segments are spread over the mirrors, a failed or corrupt one is fetched from the next
+        let urls: Vec<String> = std::iter::once(&job.url)
+            .chain(job.mirrors.iter())
+            .filter(|url| Protocol::of(url).is_ok_and(|p| p.segmented()))
+            .cloned()
+            .collect();
+        if urls.is_empty() {
+            return Some("none of the mirrors support downloading segments".into());
+        }
+        // plain segments all run at once, pieces a few at a time
+        let limit = if pieces {
+            (job.segments as usize).max(urls.len())
+        } else {
+            segments.len()
+        };
+        let mut pending: VecDeque<u32> = segments
+            .iter()
+            .filter(|s| s.start + s.downloaded <= s.end)
+            .map(|s| s.idx)
+            .collect();
+        let mut tries = vec![0; segments.len()];
+        let (event_sender, mut event_receiver) = mpsc::unbounded_channel();
+        let mut handles = Vec::new();
+        let mut running = 0;
+        let mut err = None;
+        let mut meter = SpeedMeter::new();
+        loop {
+            while running < limit {
+                let idx = match pending.pop_front() {
+                    Some(idx) => idx,
+                    None => break,
+                };
+                let url = &urls[(idx as usize + tries[idx as usize]) % urls.len()];
+                let mut request = client.get(url.clone());
+                // a changed file is sent whole, which fails the segment instead of mixing the two.
+                // mirrors don't share etags, so they rely on the hashes instead
+                if let Some(v) = validators.if_range().filter(|_| !pieces && *url == job.url) {
+                    request = request.header(IF_RANGE, v);
+                }
+                handles.push(tokio::spawn(Self::download_segment(
+                    client.clone(),
+                    request,
+                    job.part_path.clone(),
+                    segments[idx as usize].clone(),
+                    job.cancel_channel.clone(),
+                    [self.global_limiter.clone(), job.limiter.clone()],
+                    event_sender.clone(),
+                )));
+                running += 1;
+            }
+            if running == 0 || job.cancel_channel.borrow().cancel {
+                break;
+            }
+            // never closes, a sender is kept above
+            let event = match event_receiver.recv().await {
+                Some(event) => event,
+                None => break,
+            };
+            match event {
+                SegmentEvent::Progress(idx, n) => {
+                    let segment = &mut segments[idx as usize];
+                    segment.downloaded += n;
+                    state.downloaded += n;
+                    state.speed = meter.update(n);
+                    self.update_segment(job, segment.clone());
+                    self.update_state(state.clone(), job).await;
+                }
+                SegmentEvent::Finished(idx) => {
+                    running -= 1;
+                    let segment = &mut segments[idx as usize];
+                    if segment.hash.is_empty() || job.cancel_channel.borrow().cancel {
+                        continue;
+                    }
+                    let mismatch = match Self::verify_piece(job, segment).await {
+                        Ok(v) => v,
+                        Err(e) => {
+                            err = Some(e);
+                            break;
+                        }
+                    };
+                    let mismatch = match mismatch {
+                        Some(v) => v,
+                        None => continue,
+                    };
+                    tries[idx as usize] += 1;
+                    if tries[idx as usize] >= urls.len() {
+                        err = Some(
+                            format!("piece {} is corrupt on every mirror, {}", idx, mismatch)
+                                .into(),
+                        );
+                        break;
+                    }
+                    println!(
+                        "piece {} of {} is corrupt, fetching it again: {}",
+                        idx, job.name, mismatch
+                    );
+                    state.downloaded -= segment.downloaded;
+                    segment.downloaded = 0;
+                    self.update_segment(job, segment.clone());
+                    pending.push_back(idx);
+                }
+                SegmentEvent::Failed(idx, e) => {
+                    running -= 1;
+                    tries[idx as usize] += 1;
+                    if tries[idx as usize] >= urls.len() {
+                        err = Some(e);
+                        break;
+                    }
+                    println!(
+                        "segment {} of {} failed, trying another mirror: {}",
+                        idx, job.name, e.msg
+                    );
+                    pending.push_back(idx);
+                }
+            }
+        }
+        for handle in handles.iter() {
+            handle.abort();
+        }
+        // the segments stop writing before the job is let go of
+        for handle in handles {
+            let _ = handle.await;
+        }
+        if job.cancel_channel.borrow().cancel {
+            if job.cancel_channel.borrow().delete {
+                if let Err(e) = std::fs::remove_file(job.part_path.clone()) {
+                    println!("failed to remove download {}", e)
+                }
+            }
+            return None;
+        }
+        if err.is_some() {
+            return err;
+        }
+        if segments.iter().any(|s| s.start + s.downloaded <= s.end) {
+            return Some("segmented download stopped before completion".into());
+        }
+        if let Some(e) = Self::verify_file(job, state.total).await {
+            return Some(e);
+        }
+        if let Some(e) = Self::move_into_place(job).await {
+            return Some(e);
+        }
+        if let Err(e) = self.state_client.delete_segments(&job.name).await {
+            println!("failed to delete segments of a finished download {}", e)
+        }
+        state.state = State::Done;
+        self.update_state(state.clone(), job).await;
+        None
+    }
+    // media segments are fetched in parallel into files of their own and joined in order at the end,
+    // the finished ones are kept across pauses and retries
+    async fn download_hls(
+        &self,
+        job: &DownloadJob,
+        state: &mut JobInfo,
+        variant: Variant,
+    ) -> Option<DownloadError> {
+        let client = match job.client() {
+            Ok(v) => v,
+            Err(e) => return Some(e),
+        };
+        let segments = match hls::resolve(&client, &job.url, variant).await {
+            Ok(v) => v,
+            Err(e) => return Some(e),
+        };
+        if segments.is_empty() {
+            return Some("the playlist has no segments".into());
+        }
+        // a key is usually shared by many segments
+        let mut keys = HashMap::new();
+        for key in segments.iter().filter_map(|s| s.key.as_ref()) {
+            if keys.contains_key(&key.url) {
+                continue;
+            }
+            match hls::fetch_key(&client, &key.url).await {
+                Ok(v) => keys.insert(key.url.clone(), v),
+                Err(e) => return Some(e),
+            };
+        }
+        let dir = hls::segments_dir(&job.part_path);
+        if let Err(e) = create_dir_all(&dir).await {
+            return Some(format!("failed to create the segments directory: {}", e).into());
+        }
+        let mut pending = VecDeque::new();
+        let mut done = 0;
+        let mut done_bytes = 0;
+        for idx in 0..segments.len() {
+            match metadata(dir.join(idx.to_string())).await {
+                Ok(m) => {
+                    done += 1;
+                    done_bytes += m.len();
+                }
+                Err(_) => pending.push_back(idx as u32),
+            }
+        }
+        state.downloaded = done_bytes;
+        Self::hls_progress(state, done, done_bytes, segments.len());
+        self.update_state(state.clone(), job).await;
+
+        let (event_sender, mut event_receiver) = mpsc::unbounded_channel();
+        let mut handles = Vec::new();
+        let mut running = 0;
+        let mut written = vec![0; segments.len()];
+        let mut err = None;
+        let mut meter = SpeedMeter::new();
+        loop {
+            while running < job.segments.max(1) {
+                let idx = match pending.pop_front() {
+                    Some(idx) => idx,
+                    None => break,
+                };
+                let segment = segments[idx as usize].clone();
+                let key = segment
+                    .key
+                    .as_ref()
+                    .and_then(|k| Some((*keys.get(&k.url)?, k.iv)));
+                handles.push(tokio::spawn(Self::download_media_segment(
+                    client.clone(),
+                    idx,
+                    segment,
+                    key,
+                    dir.join(idx.to_string()),
+                    job.cancel_channel.clone(),
+                    [self.global_limiter.clone(), job.limiter.clone()],
+                    event_sender.clone(),
+                )));
+                running += 1;
+            }
+            if running == 0 || job.cancel_channel.borrow().cancel {
+                break;
+            }
+            // never closes, a sender is kept above
+            let event = match event_receiver.recv().await {
+                Some(event) => event,
+                None => break,
+            };
+            match event {
+                SegmentEvent::Progress(idx, n) => {
+                    written[idx as usize] += n;
+                    state.downloaded += n;
+                    state.speed = meter.update(n);
+                    self.update_state(state.clone(), job).await;
+                }
+                SegmentEvent::Finished(idx) => {
+                    running -= 1;
+                    if job.cancel_channel.borrow().cancel {
+                        continue;
+                    }
+                    done += 1;
+                    done_bytes += written[idx as usize];
+                    Self::hls_progress(state, done, done_bytes, segments.len());
+                    self.update_state(state.clone(), job).await;
+                }
+                SegmentEvent::Failed(idx, e) => {
+                    err = Some(DownloadError {
+                        msg: format!("segment {}: {}", idx, e.msg),
+                        ..e
+                    });
+                    break;
+                }
+            }
+        }
+        for handle in handles.iter() {
+            handle.abort();
+        }
+        // the segments stop writing before the job is let go of
+        for handle in handles {
+            let _ = handle.await;
+        }
+        if job.cancel_channel.borrow().cancel {
+            if job.cancel_channel.borrow().delete {
+                if let Err(e) = std::fs::remove_dir_all(&dir) {
+                    println!("failed to remove download {}", e)
+                }
+            }
+            return None;
+        }
+        if err.is_some() {
+            return err;
+        }
+        if let Err(e) = Self::join_segments(&dir, segments.len(), &job.part_path).await {
+            return Some(format!("failed to join the segments: {}", e).into());
+        }
+        state.total = metadata(&job.part_path)
+            .await
+            .map(|m| m.len())
+            .unwrap_or(state.total);
+        state.downloaded = state.total;
+        if let Some(e) = Self::verify_file(job, state.total).await {
+            return Some(e);
+        }
+        if let Some(e) = Self::move_into_place(job).await {
+            return Some(e);
+        }
+        if let Err(e) = remove_dir_all(&dir).await {
+            println!("failed to remove the segments of a finished download {}", e)
+        }
+        state.state = State::Done;
+        self.update_state(state.clone(), job).await;
+        None
+    }
+    // the size isn't known until every segment is fetched, it's guessed from the finished ones
+    fn hls_progress(state: &mut JobInfo, done: usize, done_bytes: u64, count: usize) {
+        if done > 0 {
+            state.total = (done_bytes / done as u64 * count as u64).max(state.downloaded);
+        }
+        state.msg = format!("{}/{} segments", done, count);
+    }
+    async fn join_segments(dir: &Path, count: usize, part_path: &Path) -> std::io::Result<()> {
+        let mut out = File::create(part_path).await?;
+        for idx in 0..count {
+            let mut segment = File::open(dir.join(idx.to_string())).await?;
+            tokio::io::copy(&mut segment, &mut out).await?;
+        }
+        out.flush().await
+    }
+    #[allow(clippy::too_many_arguments)]
+    async fn download_media_segment(
+        client: http::Client,
+        idx: u32,
+        segment: MediaSegment,
+        key: Option<([u8; 16], [u8; 16])>,
+        path: PathBuf,
+        cancelled: watch::Receiver<CancelInfo>,
+        limiters: [RateLimiter; 2],
+        events: mpsc::UnboundedSender<SegmentEvent>,
+    ) {
+        let event = match Self::fetch_media_segment(
+            client, idx, segment, key, path, cancelled, limiters, &events,
+        )
+        .await
+        {
+            Ok(()) => SegmentEvent::Finished(idx),
+            Err(e) => SegmentEvent::Failed(idx, e),
+        };
+        let _ = events.send(event);
+    }
+    // segments are small, they're decrypted in memory and only written once complete
+    #[allow(clippy::too_many_arguments)]
+    async fn fetch_media_segment(
+        client: http::Client,
+        idx: u32,
+        segment: MediaSegment,
+        key: Option<([u8; 16], [u8; 16])>,
+        path: PathBuf,
+        cancelled: watch::Receiver<CancelInfo>,
+        limiters: [RateLimiter; 2],
+        events: &mpsc::UnboundedSender<SegmentEvent>,
+    ) -> Result<(), DownloadError> {
+        let mut request = client.get(segment.url);
+        if let Some((start, end)) = segment.range {
+            request = request.header(RANGE, format!("bytes={}-{}", start, end));
+        }
+        let mut res = client
+            .send(request)
+            .await
+            .map_err(|e| DownloadError::from_reqwest("", e))?;
+        if let Some(e) = DownloadError::from_response(&res) {
+            return Err(e);
+        }
+        if segment.range.is_some() && res.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(format!("expected partial content, got {}", res.status()).into());
+        }
+        let mut data = Vec::new();
+        while let Some(chunk) = res
+            .chunk()
+            .await
+            .map_err(|e| DownloadError::from_reqwest("failed to download chunk: ", e))?
+        {
+            if cancelled.borrow().cancel {
+                return Ok(());
+            }
+            for limiter in limiters.iter() {
+                limiter.acquire(chunk.len() as u64).await;
+            }
+            if cancelled.borrow().cancel {
+                return Ok(());
+            }
+            data.extend_from_slice(&chunk);
+            let _ = events.send(SegmentEvent::Progress(idx, chunk.len() as u64));
+        }
+        if let Some((key, iv)) = key {
+            hls::decrypt(&key, &iv, &mut data)?;
+        }
+        // written aside first so that a segment file is always complete
+        let tmp = path.with_extension("part");
+        async {
+            let mut file = File::create(&tmp).await?;
+            file.write_all(&data).await?;
+            file.flush().await?;
+            rename(&tmp, &path).await
+        }
+        .await
+        .map_err(|e| format!("failed to save the segment: {}", e).into())
+    }
+    // the download only shows up at its final path once it's complete and verified
+    async fn move_into_place(job: &DownloadJob) -> Option<DownloadError> {
+        let res = match rename(&job.part_path, &job.file_path).await {
+            // the incomplete directory is on another filesystem, copy next to the final path
+            // first so that the file still appears there at once
+            Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+                let tmp = part_path(&job.file_path, "");
+                async {
+                    copy(&job.part_path, &tmp).await?;
+                    rename(&tmp, &job.file_path).await?;
+                    remove_file(&job.part_path).await
+                }
+                .await
+            }
+            res => res,
+        };
+        res.err()
+            .map(|e| format!("failed to move the download into place: {}", e).into())
+    }
+    // segments are written out of order, so the file is hashed once it's complete
+    async fn verify_file(job: &DownloadJob, len: u64) -> Option<DownloadError> {
+        let checksum = job.checksum.as_ref()?;
+        let mut hasher = checksum.algorithm.hasher();
+        if let Err(e) = hash_file(&job.part_path, &mut hasher, len).await {
+            return Some(format!("failed to hash the download: {}", e).into());
+        }
+        checksum.verify(hasher).map(|e| e.into())
+    }
+    // returns the mismatch if the piece doesn't match its hash
+    async fn verify_piece(
+        job: &DownloadJob,
+        segment: &Segment,
+    ) -> Result<Option<String>, DownloadError> {
+        let checksum: Checksum = segment
+            .hash
+            .parse()
+            .map_err(|e| format!("invalid hash of piece {}: {}", segment.idx, e))?;
+        let mut hasher = checksum.algorithm.hasher();
+        hash_range(
+            &job.part_path,
+            &mut hasher,
+            segment.start,
+            segment.end + 1 - segment.start,
+        )
+        .await
+        .map_err(|e| format!("failed to hash piece {}: {}", segment.idx, e))?;
+        Ok(checksum.verify(hasher))
+    }
+    async fn download_segment(
+        client: http::Client,
+        request: reqwest::RequestBuilder,
+        file_path: PathBuf,
+        segment: Segment,
+        cancelled: watch::Receiver<CancelInfo>,
+        limiters: [RateLimiter; 2],
+        events: mpsc::UnboundedSender<SegmentEvent>,
+    ) {
+        let idx = segment.idx;
+        let event = match Self::fetch_segment(
+            client, request, file_path, segment, cancelled, limiters, &events,
+        )
+        .await
+        {
+            Ok(()) => SegmentEvent::Finished(idx),
+            Err(e) => SegmentEvent::Failed(idx, e),
+        };
+        let _ = events.send(event);
+    }
+    #[allow(clippy::too_many_arguments)]
+    async fn fetch_segment(
+        client: http::Client,
+        request: reqwest::RequestBuilder,
+        file_path: PathBuf,
+        segment: Segment,
+        cancelled: watch::Receiver<CancelInfo>,
+        limiters: [RateLimiter; 2],
+        events: &mpsc::UnboundedSender<SegmentEvent>,
+    ) -> Result<(), DownloadError> {
+        let offset = segment.start + segment.downloaded;
+        let mut res = client
+            .send(request.header(RANGE, format!("bytes={}-{}", offset, segment.end)))
+            .await
+            .map_err(|e| DownloadError::from_reqwest("", e))?;
+        if let Some(e) = DownloadError::from_response(&res) {
+            return Err(e);
+        }
+        if res.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(format!(
+                "expected partial content for segment {}, got {}",
+                segment.idx,
+                res.status()
+            )
+            .into());
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(&file_path)
+            .await
+            .map_err(|e| format!("failed to open file: {}", e))?;
+        file.seek(SeekFrom::Start(offset))
+            .await
+            .map_err(|e| format!("failed to seek in file: {}", e))?;
+        let mut remaining = segment.end + 1 - offset;
+        while remaining > 0 {
+            if cancelled.borrow().cancel {
+                return Ok(());
+            }
+            let chunk = res
+                .chunk()
+                .await
+                .map_err(|e| DownloadError::from_reqwest("failed to download chunk: ", e))?;
+            match chunk {
+                Some(chunk) => {
+                    // paused while waiting, a resumed worker may already be writing the file
+                    if cancelled.borrow().cancel {
+                        return Ok(());
+                    }
+                    let n = remaining.min(chunk.len() as u64);
+                    for limiter in limiters.iter() {
+                        limiter.acquire(n).await;
+                    }
+                    if cancelled.borrow().cancel {
+                        return Ok(());
+                    }
+                    file.write_all(&chunk[..n as usize])
+                        .await
+                        .map_err(|e| format!("failed to download chunk: {}", e))?;
+                    // the progress is persisted right after, so make sure the chunk is written first
+                    file.flush()
+                        .await
+                        .map_err(|e| format!("failed to flush file: {}", e))?;
+                    remaining -= n;
+                    let _ = events.send(SegmentEvent::Progress(segment.idx, n));
+                }
+                None => {
+                    // the connection got cut, worth another try
+                    return Err(DownloadError {
+                        msg: format!("segment {} ended {} bytes early", segment.idx, remaining),
+                        transient: true,
+                        retry_after: None,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+    async fn download_stream(
+        &self,
+        job: &DownloadJob,
+        state: &mut JobInfo,
+    ) -> Option<DownloadError> {
+        let offset = match metadata(&job.part_path).await {
+            Ok(v) => v.size(),
+            // new download
+            Err(e) if e.kind() == ErrorKind::NotFound => 0,
+            Err(e) => return Some(format!("couldn't stat download path {}", e).into()),
+        };
+        state.downloaded = offset;
+        self.update_state(state.clone(), job).await;
+        let client = match job.client() {
+            Ok(v) => v,
+            Err(e) => return Some(e),
+        };
+        let mut transfer = match job
+            .protocol
+            .open(&client, &job.url, offset, &job.validators)
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => return Some(e),
+        };
+        let offset = transfer.offset;
+        transfer.validators.save(state);
+        state.downloaded = offset;
+        if let Some(total) = transfer.total {
+            state.total = total;
+        }
+        self.update_state(state.clone(), job).await;
+        let mut options = OpenOptions::new();
+        if offset > 0 {
+            options.append(true);
+        } else {
+            options.write(true).create(true).truncate(true);
+        }
+        let file = options.open(&job.part_path).await;
+        if let Err(e) = file {
+            return Some(format!("failed to create file: {}", e).into());
         }
         let mut file = file.unwrap();
+        let mut hasher = None;
+        if let Some(checksum) = &job.checksum {
+            let mut h = checksum.algorithm.hasher();
+            // a resumed download has to hash what's already on disk first
+            if let Err(e) = hash_file(&job.part_path, &mut h, state.downloaded).await {
+                return Some(format!("failed to hash the partial download: {}", e).into());
+            }
+            hasher = Some(h);
+        }
+        let mut meter = SpeedMeter::new();
         loop {
             if job.cancel_channel.borrow().cancel {
                 if job.cancel_channel.borrow().delete {
-                    if let Err(e) = std::fs::remove_file(job.file_path.clone()) {
+                    if let Err(e) = std::fs::remove_file(job.part_path.clone()) {
                         println!("failed to remove download {}", e)
                     }
                 }
                 return None;
             }
-            let chunk = res.chunk().await;
+            let chunk = transfer.chunk().await;
             match chunk {
                 Ok(chunk) => match chunk {
-                    Some(chunk) => match file.write_all(&chunk).await {
-                        Ok(_) => {
-                            state.downloaded += chunk.len() as u64;
-                            self.update_state(state.clone(), &job.cancel_channel).await;
+                    Some(chunk) => {
+                        // paused while waiting, nothing more is written once resumed by another
+                        // worker, the check at the top of the loop stops this one
+                        if job.cancel_channel.borrow().cancel {
+                            continue;
                         }
-                        Err(e) => {
-                            return Some(format!("failed to download chunk: {}", e.to_string()));
+                        self.global_limiter.acquire(chunk.len() as u64).await;
+                        job.limiter.acquire(chunk.len() as u64).await;
+                        if job.cancel_channel.borrow().cancel {
+                            continue;
+                        }
+                        match file.write_all(&chunk).await {
+                            Ok(_) => {
+                                if let Some(h) = hasher.as_mut() {
+                                    h.update(&chunk);
+                                }
+                                state.downloaded += chunk.len() as u64;
+                                state.speed = meter.update(chunk.len() as u64);
+                                self.update_state(state.clone(), job).await;
+                            }
+                            Err(e) => {
+                                return Some(format!("failed to download chunk: {}", e).into());
+                            }
                         }
-                    },
+                    }
                     None => {
+                        if let (Some(checksum), Some(h)) = (&job.checksum, hasher.take()) {
+                            if let Some(e) = checksum.verify(h) {
+                                return Some(e.into());
+                            }
+                        }
+                        if let Some(e) = Self::move_into_place(job).await {
+                            return Some(e);
+                        }
                         state.state = State::Done;
-                        self.update_state(state.clone(), &job.cancel_channel).await;
+                        self.update_state(state.clone(), job).await;
                         return None;
                     }
                 },
-                Err(e) => {
-                    return Some(format!("failed to download chunk: {}", e.to_string()));
-                }
+                Err(e) => return Some(e),
             }
         }
     }
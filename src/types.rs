@@ -1,4 +1,9 @@
+use crate::err::{ManagerError, ManagerErrorKind};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, IF_RANGE, RANGE, REFERER, USER_AGENT};
+use serde::{Deserialize, Serialize};
 use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Clone, Debug)]
 pub struct JobInfo {
@@ -9,12 +14,113 @@ pub struct JobInfo {
     pub total: u64,
     pub state: State,
     pub msg: String,
+    pub segments: u32,
+    pub checksum: String,
+    pub attempts: u32,
+    pub max_attempts: u32,
+    // unix timestamp of the next automatic retry, 0 if none is scheduled
+    pub next_retry: u64,
+    // bytes per second, 0 if the download isn't limited
+    pub rate_limit: u64,
+    // smoothed bytes per second, 0 when the download isn't running
+    pub speed: u64,
+    // unix timestamps, 0 if the download hasn't started or finished yet
+    pub started_at: u64,
+    pub finished_at: u64,
+    // higher priorities are downloaded first, then lower positions
+    pub priority: i32,
+    pub position: i64,
+    // unix timestamp before which the download shouldn't start, 0 to start right away
+    pub start_at: u64,
+    // validators of the remote file, checked before resuming so a changed file isn't mixed
+    // with the old one
+    pub etag: String,
+    pub last_modified: String,
+    // more urls of the same file, segments are spread over all of them
+    pub mirrors: Vec<String>,
+    // the rendition of an hls playlist to download, empty for other downloads
+    pub variant: String,
+    pub request: RequestOptions,
+    // a netscape cookies.txt given to the job, sent along with the jar's but only by its requests
+    pub cookies_txt: String,
+    // the proxy the last attempt went through without its password, empty if none
+    pub used_proxy: String,
+}
+
+// sent with every http request of a download, resuming included
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RequestOptions {
+    // "Name: value"
+    pub headers: Vec<String>,
+    // "name=value"
+    pub cookies: Vec<String>,
+    pub user_agent: String,
+    pub referer: String,
+    // a proxy url or direct, the daemon's proxy settings apply if empty
+    pub proxy: String,
+}
+
+impl RequestOptions {
+    // also checks the headers and cookies, the ranges are left to the downloads
+    pub fn header_map(&self) -> Result<HeaderMap, ManagerError> {
+        let mut headers = HeaderMap::new();
+        for header in self.headers.iter() {
+            let (name, value) = header.split_once(':').ok_or_else(|| {
+                invalid_header(format!("{} should look like Name: value", header))
+            })?;
+            let name = HeaderName::from_str(name.trim())
+                .map_err(|_| invalid_header(format!("invalid header name {:?}", name.trim())))?;
+            if name == RANGE || name == IF_RANGE {
+                return Err(invalid_header(format!("{} is set by the download", name)));
+            }
+            headers.append(name, header_value(value.trim())?);
+        }
+        // sent along with the ones of the cookie jar
+        if let Some(cookie) = self.cookies.iter().find(|c| !c.contains('=')) {
+            return Err(invalid_header(format!(
+                "{} should look like name=value",
+                cookie
+            )));
+        }
+        header_value(&self.cookies.join("; "))?;
+        if !self.user_agent.is_empty() {
+            headers.insert(USER_AGENT, header_value(&self.user_agent)?);
+        }
+        if !self.referer.is_empty() {
+            headers.insert(REFERER, header_value(&self.referer)?);
+        }
+        Ok(headers)
+    }
+}
+
+fn header_value(v: &str) -> Result<HeaderValue, ManagerError> {
+    HeaderValue::from_str(v).map_err(|_| invalid_header(format!("invalid header value {:?}", v)))
+}
+
+fn invalid_header(msg: String) -> ManagerError {
+    ManagerError {
+        kind: ManagerErrorKind::InvalidHeader,
+        msg,
+    }
+}
+
+// a byte range of a segmented download, end is inclusive
+#[derive(Clone, Debug)]
+pub struct Segment {
+    pub idx: u32,
+    pub start: u64,
+    pub end: u64,
+    pub downloaded: u64,
+    // the expected checksum of the range, empty if unknown
+    pub hash: String,
 }
 
 #[derive(PartialEq, Clone, Debug)]
 pub enum State {
     Active,
     Pending,
+    Paused,
     Cancelled,
     Failed,
     Done,
@@ -29,6 +135,7 @@ impl fmt::Display for State {
             match *self {
                 State::Active => "Active",
                 State::Pending => "Pending",
+                State::Paused => "Paused",
                 State::Failed => "Failed",
                 State::Cancelled => "Cancelled",
                 State::Done => "Done",
@@ -37,3 +144,10 @@ impl fmt::Display for State {
         )
     }
 }
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}